@@ -0,0 +1,35 @@
+CREATE TYPE folder_visibility AS ENUM ('inherit', 'public', 'private');
+
+ALTER TABLE folders
+    ADD COLUMN visibility folder_visibility NOT NULL DEFAULT 'inherit';
+
+-- first folder up the tree that doesn't inherit decides, top level folders that inherit are private
+CREATE OR REPLACE FUNCTION folder_is_public(start_id UUID)
+    RETURNS BOOLEAN
+    LANGUAGE SQL
+AS
+$$
+WITH
+    RECURSIVE ancestors
+                  AS
+                  (SELECT id,
+                          parent_id,
+                          visibility,
+                          1 AS lvl
+                   FROM folders
+                   WHERE id = start_id
+
+                   UNION ALL
+
+                   SELECT f.id,
+                          f.parent_id,
+                          f.visibility,
+                          a.lvl + 1
+                   FROM folders f
+                            JOIN ancestors a ON a.parent_id = f.id)
+SELECT COALESCE((SELECT visibility = 'public'
+                 FROM ancestors
+                 WHERE visibility <> 'inherit'
+                 ORDER BY lvl
+                 LIMIT 1), FALSE);
+$$;
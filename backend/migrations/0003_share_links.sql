@@ -0,0 +1,34 @@
+CREATE TABLE share_links
+(
+    id            UUID PRIMARY KEY                              DEFAULT uuidv7(),
+    owner_id      UUID        NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    file_id       UUID REFERENCES files (id) ON DELETE CASCADE,
+    folder_id     UUID REFERENCES folders (id) ON DELETE CASCADE,
+    password      TEXT,
+    max_downloads INTEGER,
+    downloads     INTEGER     NOT NULL                          DEFAULT 0,
+    expires_at    TIMESTAMPTZ,
+    created_at    TIMESTAMPTZ NOT NULL                          DEFAULT NOW(),
+    CHECK ((file_id IS NULL) <> (folder_id IS NULL))
+);
+
+CREATE INDEX idx_share_links_owner_id ON share_links (owner_id);
+CREATE INDEX idx_share_links_file_id ON share_links (file_id);
+CREATE INDEX idx_share_links_folder_id ON share_links (folder_id);
+
+CREATE OR REPLACE FUNCTION folder_is_within(start_id UUID, ancestor_id UUID)
+    RETURNS BOOLEAN
+    LANGUAGE SQL
+AS
+$$
+WITH RECURSIVE ancestors AS (SELECT id, parent_id
+                             FROM folders
+                             WHERE id = start_id
+
+                             UNION ALL
+
+                             SELECT f.id, f.parent_id
+                             FROM folders f
+                                      JOIN ancestors a ON a.parent_id = f.id)
+SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = ancestor_id);
+$$;
@@ -0,0 +1,26 @@
+-- NULL folder_id means the root folder, it has to be unique per user just like every other folder
+UPDATE permissions p
+SET read   = agg.read,
+    modify = agg.modify,
+    edit   = agg.edit
+FROM (SELECT user_id, BOOL_OR(read) AS read, BOOL_OR(modify) AS modify, BOOL_OR(edit) AS edit
+      FROM permissions
+      WHERE folder_id IS NULL
+      GROUP BY user_id) agg
+WHERE p.user_id = agg.user_id
+  AND p.folder_id IS NULL;
+
+DELETE
+FROM permissions a
+    USING permissions b
+WHERE a.user_id = b.user_id
+  AND a.folder_id IS NULL
+  AND b.folder_id IS NULL
+  AND a.id < b.id;
+
+ALTER TABLE permissions
+    DROP CONSTRAINT permissions_user_id_folder_id_key;
+DROP INDEX idx_permissions_user_folder;
+
+ALTER TABLE permissions
+    ADD CONSTRAINT permissions_user_id_folder_id_key UNIQUE NULLS NOT DISTINCT (user_id, folder_id);
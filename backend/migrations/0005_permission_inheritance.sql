@@ -0,0 +1,40 @@
+-- permissions of the nearest folder up the tree that has an explicit row for the user,
+-- the root folder (NULL) is the last one checked
+CREATE OR REPLACE FUNCTION get_effective_permission(target_user UUID, start_id UUID)
+    RETURNS TABLE
+            (
+                read   BOOLEAN,
+                modify BOOLEAN,
+                edit   BOOLEAN
+            )
+    LANGUAGE SQL
+AS
+$$
+WITH
+    RECURSIVE ancestors
+                  AS
+                  (SELECT id,
+                          parent_id,
+                          1 AS lvl
+                   FROM folders
+                   WHERE id = start_id
+
+                   UNION ALL
+
+                   SELECT f.id,
+                          f.parent_id,
+                          a.lvl + 1
+                   FROM folders f
+                            JOIN ancestors a ON a.parent_id = f.id),
+    chain AS (SELECT id, lvl
+              FROM ancestors
+
+              UNION ALL
+
+              SELECT NULL, 2147483647)
+SELECT p.read, p.modify, p.edit
+FROM chain c
+         JOIN permissions p ON p.folder_id IS NOT DISTINCT FROM c.id AND p.user_id = target_user
+ORDER BY c.lvl
+LIMIT 1;
+$$;
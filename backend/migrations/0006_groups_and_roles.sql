@@ -0,0 +1,83 @@
+CREATE TABLE groups
+(
+    id         UUID PRIMARY KEY     DEFAULT uuidv7(),
+    name       TEXT UNIQUE NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE group_members
+(
+    group_id   UUID        NOT NULL REFERENCES groups (id) ON DELETE CASCADE,
+    user_id    UUID        NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (group_id, user_id)
+);
+
+CREATE INDEX idx_group_members_user_id ON group_members (user_id);
+
+CREATE TRIGGER set_groups_updated
+    BEFORE UPDATE
+    ON groups
+    FOR EACH ROW
+EXECUTE FUNCTION set_updated_at();
+
+-- roles are presets of permission flags, NULL means the flags were set by hand
+CREATE TYPE permission_role AS ENUM ('viewer', 'uploader', 'maintainer');
+
+ALTER TABLE permissions
+    ALTER COLUMN user_id DROP NOT NULL,
+    ADD COLUMN group_id UUID REFERENCES groups (id) ON DELETE CASCADE,
+    ADD COLUMN role     permission_role,
+    ADD CONSTRAINT permissions_user_or_group CHECK ((user_id IS NULL) <> (group_id IS NULL)),
+    DROP CONSTRAINT permissions_user_id_folder_id_key;
+
+CREATE UNIQUE INDEX idx_permissions_user_folder ON permissions (user_id, folder_id) NULLS NOT DISTINCT
+    WHERE user_id IS NOT NULL;
+CREATE UNIQUE INDEX idx_permissions_group_folder ON permissions (group_id, folder_id) NULLS NOT DISTINCT
+    WHERE group_id IS NOT NULL;
+
+-- same as before but grants of the user's groups count as well,
+-- everything given on the nearest folder with any grant is combined
+CREATE OR REPLACE FUNCTION get_effective_permission(target_user UUID, start_id UUID)
+    RETURNS TABLE
+            (
+                read   BOOLEAN,
+                modify BOOLEAN,
+                edit   BOOLEAN
+            )
+    LANGUAGE SQL
+AS
+$$
+WITH
+    RECURSIVE ancestors
+                  AS
+                  (SELECT id,
+                          parent_id,
+                          1 AS lvl
+                   FROM folders
+                   WHERE id = start_id
+
+                   UNION ALL
+
+                   SELECT f.id,
+                          f.parent_id,
+                          a.lvl + 1
+                   FROM folders f
+                            JOIN ancestors a ON a.parent_id = f.id),
+    chain AS (SELECT id, lvl
+              FROM ancestors
+
+              UNION ALL
+
+              SELECT NULL, 2147483647),
+    grants AS (SELECT c.lvl, p.read, p.modify, p.edit
+               FROM chain c
+                        JOIN permissions p ON p.folder_id IS NOT DISTINCT FROM c.id
+               WHERE p.user_id = target_user
+                  OR p.group_id IN (SELECT group_id FROM group_members WHERE user_id = target_user))
+SELECT BOOL_OR(read), BOOL_OR(modify), BOOL_OR(edit)
+FROM grants
+WHERE lvl = (SELECT MIN(lvl) FROM grants)
+HAVING COUNT(*) > 0;
+$$;
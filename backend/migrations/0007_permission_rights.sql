@@ -0,0 +1,88 @@
+-- read/modify/edit are split into one flag per operation
+ALTER TABLE permissions
+    ADD COLUMN list               BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN upload             BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN rename             BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN move               BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN delete             BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN share              BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN manage_permissions BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- edit allowed creating, uploading, renaming and deleting files,
+-- modify allowed renaming, moving, deleting and sharing folders
+UPDATE permissions
+SET list   = read,
+    upload = edit,
+    rename = modify OR edit,
+    move   = modify,
+    delete = modify OR edit,
+    share  = modify;
+
+ALTER TABLE permissions
+    DROP COLUMN modify,
+    DROP COLUMN edit;
+
+DROP FUNCTION get_effective_permission(UUID, UUID);
+
+CREATE OR REPLACE FUNCTION get_effective_permission(target_user UUID, start_id UUID)
+    RETURNS TABLE
+            (
+                list               BOOLEAN,
+                read               BOOLEAN,
+                upload             BOOLEAN,
+                rename             BOOLEAN,
+                move               BOOLEAN,
+                delete             BOOLEAN,
+                share              BOOLEAN,
+                manage_permissions BOOLEAN
+            )
+    LANGUAGE SQL
+AS
+$$
+WITH
+    RECURSIVE ancestors
+                  AS
+                  (SELECT id,
+                          parent_id,
+                          1 AS lvl
+                   FROM folders
+                   WHERE id = start_id
+
+                   UNION ALL
+
+                   SELECT f.id,
+                          f.parent_id,
+                          a.lvl + 1
+                   FROM folders f
+                            JOIN ancestors a ON a.parent_id = f.id),
+    chain AS (SELECT id, lvl
+              FROM ancestors
+
+              UNION ALL
+
+              SELECT NULL, 2147483647),
+    grants AS (SELECT c.lvl,
+                      p.list,
+                      p.read,
+                      p.upload,
+                      p.rename,
+                      p.move,
+                      p.delete,
+                      p.share,
+                      p.manage_permissions
+               FROM chain c
+                        JOIN permissions p ON p.folder_id IS NOT DISTINCT FROM c.id
+               WHERE p.user_id = target_user
+                  OR p.group_id IN (SELECT group_id FROM group_members WHERE user_id = target_user))
+SELECT BOOL_OR(g.list),
+       BOOL_OR(g.read),
+       BOOL_OR(g.upload),
+       BOOL_OR(g.rename),
+       BOOL_OR(g.move),
+       BOOL_OR(g.delete),
+       BOOL_OR(g.share),
+       BOOL_OR(g.manage_permissions)
+FROM grants g
+WHERE g.lvl = (SELECT MIN(lvl) FROM grants)
+HAVING COUNT(*) > 0;
+$$;
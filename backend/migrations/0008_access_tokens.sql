@@ -0,0 +1,19 @@
+CREATE TYPE token_scope AS ENUM ('read', 'upload', 'admin');
+
+-- personal access tokens for scripts and CI, only a hash of the token is stored
+CREATE TABLE access_tokens
+(
+    id           UUID PRIMARY KEY     DEFAULT uuidv7(),
+    user_id      UUID        NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name         TEXT        NOT NULL,
+    token_hash   TEXT UNIQUE NOT NULL,
+    scope        token_scope NOT NULL,
+    -- limits the token to this folder and everything inside it
+    folder_id    UUID REFERENCES folders (id) ON DELETE CASCADE,
+    expires_at   TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (user_id, name)
+);
+
+CREATE INDEX idx_access_tokens_user_id ON access_tokens (user_id);
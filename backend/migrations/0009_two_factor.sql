@@ -0,0 +1,31 @@
+-- the secret is saved when enrolment starts and only used after the first code confirms it
+ALTER TABLE users
+    ADD COLUMN totp_secret    TEXT,
+    ADD COLUMN totp_enabled   BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN totp_last_step BIGINT;
+
+CREATE TABLE recovery_codes
+(
+    id         UUID PRIMARY KEY     DEFAULT uuidv7(),
+    user_id    UUID        NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    code_hash  TEXT        NOT NULL,
+    used_at    TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (user_id, code_hash)
+);
+
+-- server wide settings changed by admins, there is always exactly one row
+CREATE TABLE settings
+(
+    id                BOOLEAN PRIMARY KEY  DEFAULT TRUE CHECK (id),
+    require_admin_2fa BOOLEAN     NOT NULL DEFAULT FALSE,
+    updated_at        TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO settings DEFAULT VALUES;
+
+CREATE TRIGGER set_settings_updated
+    BEFORE UPDATE
+    ON settings
+    FOR EACH ROW
+EXECUTE FUNCTION set_updated_at();
@@ -0,0 +1,15 @@
+-- webauthn credentials, only ES256 keys are accepted so the key is kept as an uncompressed P-256 point
+CREATE TABLE passkeys
+(
+    id            UUID PRIMARY KEY      DEFAULT uuidv7(),
+    user_id       UUID         NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name          TEXT         NOT NULL,
+    credential_id BYTEA UNIQUE NOT NULL,
+    public_key    BYTEA        NOT NULL,
+    sign_count    BIGINT       NOT NULL DEFAULT 0,
+    last_used_at  TIMESTAMPTZ,
+    created_at    TIMESTAMPTZ  NOT NULL DEFAULT NOW(),
+    UNIQUE (user_id, name)
+);
+
+CREATE INDEX idx_passkeys_user_id ON passkeys (user_id);
@@ -0,0 +1,17 @@
+-- accounts created by single sign-on don't have a local password
+ALTER TABLE users
+    ALTER COLUMN password DROP NOT NULL;
+
+-- links a subject of an OpenID Connect provider to a user
+CREATE TABLE user_identities
+(
+    id            UUID PRIMARY KEY     DEFAULT uuidv7(),
+    user_id       UUID        NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    issuer        TEXT        NOT NULL,
+    subject       TEXT        NOT NULL,
+    last_login_at TIMESTAMPTZ,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (issuer, subject)
+);
+
+CREATE INDEX idx_user_identities_user_id ON user_identities (user_id);
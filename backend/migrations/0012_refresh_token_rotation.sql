@@ -0,0 +1,9 @@
+-- refresh tokens that were already exchanged for a new one, presenting one again means it was stolen
+CREATE TABLE used_refresh_tokens
+(
+    refresh_token UUID PRIMARY KEY,
+    token_id      UUID        NOT NULL REFERENCES user_tokens (id) ON DELETE CASCADE,
+    used_at       TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_used_refresh_tokens_token_id ON used_refresh_tokens (token_id);
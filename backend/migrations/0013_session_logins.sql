@@ -0,0 +1,3 @@
+-- logins without "remember me" get a short lifetime that is renewed with activity
+ALTER TABLE user_tokens
+    ADD COLUMN stay_logged_in BOOLEAN NOT NULL DEFAULT TRUE;
@@ -0,0 +1,23 @@
+-- access cookies carry the generation they were issued with, a different one makes them refresh
+ALTER TABLE users
+    ADD COLUMN token_generation INTEGER NOT NULL DEFAULT 0;
+
+CREATE OR REPLACE FUNCTION bump_token_generation()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF NEW.password IS DISTINCT FROM OLD.password
+        OR NEW.admin IS DISTINCT FROM OLD.admin
+        OR NEW.login IS DISTINCT FROM OLD.login
+        OR NEW.username IS DISTINCT FROM OLD.username THEN
+        NEW.token_generation = OLD.token_generation + 1;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER bump_users_token_generation
+    BEFORE UPDATE
+    ON users
+    FOR EACH ROW
+EXECUTE FUNCTION bump_token_generation();
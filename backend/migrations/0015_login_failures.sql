@@ -0,0 +1,15 @@
+CREATE TYPE login_failure_kind AS ENUM ('account', 'ip');
+
+-- failed logins per login name and per client address, kept in the database so restarts don't reset them
+CREATE TABLE login_failures
+(
+    kind            login_failure_kind NOT NULL,
+    -- the login as typed, so unknown accounts are limited the same way as existing ones
+    subject         TEXT               NOT NULL,
+    failures        INTEGER            NOT NULL DEFAULT 0,
+    locked_until    TIMESTAMPTZ,
+    last_failure_at TIMESTAMPTZ        NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (kind, subject)
+);
+
+CREATE INDEX idx_login_failures_last_failure_at ON login_failures (last_failure_at);
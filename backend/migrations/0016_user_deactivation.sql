@@ -0,0 +1,18 @@
+-- deactivated accounts keep their files but can't log in or refresh their sessions
+ALTER TABLE users
+    ADD COLUMN deactivated BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE OR REPLACE FUNCTION bump_token_generation()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF NEW.password IS DISTINCT FROM OLD.password
+        OR NEW.admin IS DISTINCT FROM OLD.admin
+        OR NEW.login IS DISTINCT FROM OLD.login
+        OR NEW.username IS DISTINCT FROM OLD.username
+        OR NEW.deactivated IS DISTINCT FROM OLD.deactivated THEN
+        NEW.token_generation = OLD.token_generation + 1;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,7 @@
+-- the first admin is created once, deleting every user later doesn't open the setup again
+ALTER TABLE settings
+    ADD COLUMN setup_completed BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE settings
+SET setup_completed = TRUE
+WHERE EXISTS (SELECT 1 FROM users);
@@ -0,0 +1,29 @@
+-- links that let someone create their own account, only a hash of the token is stored
+CREATE TABLE invites
+(
+    id         UUID PRIMARY KEY     DEFAULT uuidv7(),
+    token_hash TEXT UNIQUE NOT NULL,
+    created_by UUID        REFERENCES users (id) ON DELETE SET NULL,
+    max_uses   INTEGER     NOT NULL DEFAULT 1 CHECK (max_uses > 0),
+    uses       INTEGER     NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- given to every account created with the invite, same flags as in permissions
+CREATE TABLE invite_permissions
+(
+    id                 UUID PRIMARY KEY DEFAULT uuidv7(),
+    invite_id          UUID    NOT NULL REFERENCES invites (id) ON DELETE CASCADE,
+    folder_id          UUID REFERENCES folders (id) ON DELETE CASCADE,
+    role               permission_role,
+    list               BOOLEAN NOT NULL DEFAULT FALSE,
+    read               BOOLEAN NOT NULL DEFAULT FALSE,
+    upload             BOOLEAN NOT NULL DEFAULT FALSE,
+    rename             BOOLEAN NOT NULL DEFAULT FALSE,
+    move               BOOLEAN NOT NULL DEFAULT FALSE,
+    delete             BOOLEAN NOT NULL DEFAULT FALSE,
+    share              BOOLEAN NOT NULL DEFAULT FALSE,
+    manage_permissions BOOLEAN NOT NULL DEFAULT FALSE,
+    UNIQUE NULLS NOT DISTINCT (invite_id, folder_id)
+);
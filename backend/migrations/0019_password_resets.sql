@@ -0,0 +1,12 @@
+-- one-time links for choosing a new password, issued by an admin, only a hash of the token is stored
+CREATE TABLE password_resets
+(
+    id         UUID PRIMARY KEY     DEFAULT uuidv7(),
+    user_id    UUID        NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    token_hash TEXT UNIQUE NOT NULL,
+    created_by UUID        REFERENCES users (id) ON DELETE SET NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_password_resets_user_id ON password_resets (user_id);
@@ -0,0 +1,14 @@
+CREATE TYPE passkey_challenge_kind AS ENUM ('registration', 'login');
+
+-- challenges for registering or using a passkey, deleted when they are answered so each works only once
+CREATE TABLE passkey_challenges
+(
+    id         UUID PRIMARY KEY                DEFAULT uuidv7(),
+    kind       passkey_challenge_kind NOT NULL,
+    challenge  TEXT                   NOT NULL,
+    -- set when the challenge can only be answered by a passkey of this user
+    user_id    UUID REFERENCES users (id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ            NOT NULL
+);
+
+CREATE INDEX idx_passkey_challenges_expires_at ON passkey_challenges (expires_at);
@@ -0,0 +1,7 @@
+-- accounts from the directory are linked through an identity with the `ldap` issuer and the directory login,
+-- the only password-less accounts without one were created by LDAP logins
+INSERT INTO user_identities (user_id, issuer, subject)
+SELECT id, 'ldap', login
+FROM users
+WHERE password IS NULL
+  AND NOT EXISTS (SELECT 1 FROM user_identities WHERE user_identities.user_id = users.id);
@@ -1,10 +1,13 @@
 use crate::auth::{AuthUser, UserData};
-use crate::models::{ApiResponse, File, Folder};
-use crate::perms::{check_permission, PermissionKind};
+use crate::models::{ApiResponse, File, Folder, Visibility};
+use crate::perms::{check_can_manage, check_permission, check_read_permission, PermissionKind};
+use crate::serve::{serve_file, FileContent, Preconditions};
+use crate::share::{count_share_download, use_share_link};
 use crate::ApiResult;
 use crate::FILES_DIR;
 use chrono::{DateTime, Utc};
 use rocket::form::Form;
+use rocket::http::CookieJar;
 use rocket::{fs::TempFile, http::Status, post, serde::json::Json, State};
 use serde::{Deserialize, Serialize};
 use sqlx::{Acquire, FromRow, PgConnection, PgPool};
@@ -112,11 +115,11 @@ pub async fn get_folder(id: Uuid, pool: &State<PgPool>, auth: AuthUser) -> ApiRe
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
-    check_permission(&mut *tx, &auth, Some(id), PermissionKind::Read).await?;
+    check_permission(&mut tx, &auth, Some(id), PermissionKind::List).await?;
 
     let folder = sqlx::query_as!(
         Folder,
-        "SELECT id, parent_id, name, owner_id, created_at, updated_at FROM folders WHERE id = $1",
+        r#"SELECT id, parent_id, name, owner_id, visibility AS "visibility: Visibility", created_at, updated_at FROM folders WHERE id = $1"#,
         id
     )
     .fetch_optional(&mut *tx)
@@ -135,6 +138,7 @@ pub async fn get_folder(id: Uuid, pool: &State<PgPool>, auth: AuthUser) -> ApiRe
 pub struct NewFolderData {
     pub name: String,
     pub parent: Option<Uuid>,
+    pub visibility: Option<Visibility>,
 }
 
 #[post("/folder", format = "json", data = "<data>")]
@@ -149,14 +153,22 @@ pub async fn create_folder(
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
-    check_permission(&mut tx, &auth, data.parent, PermissionKind::Edit).await?;
+    check_permission(&mut tx, &auth, data.parent, PermissionKind::Upload).await?;
+    // like in `edit_folder` choosing who can see the folder is part of managing permissions
+    if data
+        .visibility
+        .is_some_and(|visibility| visibility != Visibility::Inherit)
+    {
+        check_can_manage(&mut tx, &auth, data.parent).await?;
+    }
     check_name(&data.name)?;
 
     let folder_id = sqlx::query_scalar!(
-        "INSERT INTO folders (name, parent_id, owner_id) VALUES ($1, $2, $3) RETURNING id",
+        "INSERT INTO folders (name, parent_id, owner_id, visibility) VALUES ($1, $2, $3, $4) RETURNING id",
         data.name,
         data.parent,
-        auth.user_id
+        auth.user_id,
+        data.visibility.unwrap_or_default() as Visibility,
     )
     .fetch_one(&mut *tx)
     .await;
@@ -180,7 +192,7 @@ pub async fn create_folder(
     };
 
     sqlx::query!(
-            "INSERT INTO permissions (user_id, folder_id, read, modify, edit) VALUES ($1, $2, TRUE, TRUE, TRUE)",
+            "INSERT INTO permissions (user_id, folder_id, list, read, upload, rename, move, delete, share, manage_permissions) VALUES ($1, $2, TRUE, TRUE, TRUE, TRUE, TRUE, TRUE, TRUE, TRUE)",
             auth.user_id,
             folder_id,
         )
@@ -234,7 +246,7 @@ pub async fn delete_folder(
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
-    check_permission(&mut tx, &auth, Some(data.id), PermissionKind::Modify).await?;
+    check_permission(&mut tx, &auth, Some(data.id), PermissionKind::Delete).await?;
 
     let mut base = PathBuf::from(FILES_DIR.get().unwrap());
     base.push(&get_folder_path(&mut tx, Some(data.id)).await?);
@@ -272,7 +284,8 @@ pub async fn delete_folder(
 #[derive(Serialize, Deserialize)]
 pub struct EditFolderData {
     pub id: Uuid,
-    pub name: String,
+    pub name: Option<String>,
+    pub visibility: Option<Visibility>,
 }
 
 #[patch("/folder/rename", format = "json", data = "<data>")]
@@ -282,55 +295,82 @@ pub async fn edit_folder(
     auth: AuthUser,
 ) -> ApiResult {
     let auth = auth?;
+    if data.name.is_none() && data.visibility.is_none() {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "nothing to change",
+            None,
+        ));
+    }
+
     let mut tx = pool
         .begin()
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
-    check_permission(&mut tx, &auth, Some(data.id), PermissionKind::Modify).await?;
-    check_name(&data.name)?;
-    let old_path = &get_folder_path(&mut tx, Some(data.id)).await?;
+    // visibility decides who can see the folder so it's part of managing its permissions
+    if let Some(visibility) = data.visibility {
+        check_can_manage(&mut tx, &auth, Some(data.id)).await?;
+        let result = sqlx::query!(
+            "UPDATE folders SET visibility = $1 WHERE id = $2",
+            visibility as Visibility,
+            data.id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
-    let result = sqlx::query!(
-        "UPDATE folders SET name = $1 WHERE id = $2",
-        data.name,
-        data.id,
-    )
-    .execute(&mut *tx)
-    .await
-    .map_err(|e| {
-        if let sqlx::Error::Database(db_err) = &e
-            && db_err.is_unique_violation()
-        {
-            ApiResponse::fail(
-                Status::Conflict,
-                "folder with this name already exists",
+        if result.rows_affected() == 0 {
+            return Err(ApiResponse::fail(
+                Status::NotFound,
+                "folder not found",
                 None,
-            )
-        } else {
-            ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+            ));
         }
-    })?;
-
-    if result.rows_affected() == 0 {
-        return Err(ApiResponse::fail(
-            Status::NotFound,
-            "folder not found",
-            None,
-        ));
     }
 
-    fs::rename(
-        FILES_DIR.get().unwrap().to_string() + &old_path,
-        FILES_DIR.get().unwrap().to_string() + &remove_last_path(&old_path) + "/" + &data.name,
-    )
-    .map_err(|e| {
-        ApiResponse::fail(
-            Status::InternalServerError,
-            "error while renaming folder",
-            Some(&e),
+    if let Some(name) = &data.name {
+        check_permission(&mut tx, &auth, Some(data.id), PermissionKind::Rename).await?;
+        check_name(name)?;
+        let old_path = &get_folder_path(&mut tx, Some(data.id)).await?;
+
+        let result = sqlx::query!("UPDATE folders SET name = $1 WHERE id = $2", name, data.id,)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| {
+                if let sqlx::Error::Database(db_err) = &e
+                    && db_err.is_unique_violation()
+                {
+                    ApiResponse::fail(
+                        Status::Conflict,
+                        "folder with this name already exists",
+                        None,
+                    )
+                } else {
+                    ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+                }
+            })?;
+
+        if result.rows_affected() == 0 {
+            return Err(ApiResponse::fail(
+                Status::NotFound,
+                "folder not found",
+                None,
+            ));
+        }
+
+        fs::rename(
+            FILES_DIR.get().unwrap().to_string() + old_path,
+            FILES_DIR.get().unwrap().to_string() + &remove_last_path(old_path) + "/" + name,
         )
-    })?;
+        .map_err(|e| {
+            ApiResponse::fail(
+                Status::InternalServerError,
+                "error while renaming folder",
+                Some(&e),
+            )
+        })?;
+    }
 
     tx.commit()
         .await
@@ -338,7 +378,7 @@ pub async fn edit_folder(
 
     Ok((
         Status::NoContent,
-        ApiResponse::success_with("renamed folder"),
+        ApiResponse::success_with("edited folder"),
     ))
 }
 
@@ -350,9 +390,11 @@ pub async fn get_all_folders(pool: &State<PgPool>, auth: AuthUser) -> ApiResult<
         sqlx::query_as!(
             Folder,
             r#"
-                SELECT f.id, f.parent_id, f.name, f.owner_id, f.created_at, f.updated_at
+                SELECT f.id, f.parent_id, f.name, f.owner_id, f.visibility AS "visibility: Visibility", f.created_at, f.updated_at
                 FROM folders f
-            "#
+                WHERE ($1::uuid IS NULL OR folder_is_within(f.id, $1))
+            "#,
+            auth.token_folder()
         )
         .fetch_all(pool.inner())
         .await
@@ -360,13 +402,13 @@ pub async fn get_all_folders(pool: &State<PgPool>, auth: AuthUser) -> ApiResult<
         sqlx::query_as!(
             Folder,
             r#"
-        SELECT f.id, f.parent_id, f.name, f.owner_id, f.created_at, f.updated_at
+        SELECT f.id, f.parent_id, f.name, f.owner_id, f.visibility AS "visibility: Visibility", f.created_at, f.updated_at
         FROM folders f
-        JOIN permissions p ON p.folder_id IS NOT DISTINCT FROM f.id
-        WHERE p.user_id = $1
-          AND p.read = TRUE
+        WHERE (SELECT ep.list FROM get_effective_permission($1, f.id) ep)
+          AND ($2::uuid IS NULL OR folder_is_within(f.id, $2))
         "#,
-            auth.user_id
+            auth.user_id,
+            auth.token_folder()
         )
         .fetch_all(pool.inner())
         .await
@@ -389,31 +431,33 @@ pub async fn get_folders(
     let result = if auth.admin {
         sqlx::query_as::<_, Folder>(&format!(
             r#"
-            SELECT f.id, f.parent_id, f.name, f.owner_id, f.created_at, f.updated_at
+            SELECT f.id, f.parent_id, f.name, f.owner_id, f.visibility, f.created_at, f.updated_at
             FROM folders f
             WHERE f.parent_id IS NOT DISTINCT FROM $1
+              AND ($2::uuid IS NULL OR folder_is_within(f.id, $2))
             ORDER BY {}
         "#,
             order_sql
         ))
         .bind(parent)
+        .bind(auth.token_folder())
         .fetch_all(pool.inner())
         .await
     } else {
         sqlx::query_as::<_, Folder>(&format!(
             r#"
-            SELECT f.id, f.parent_id, f.name, f.owner_id, f.created_at, f.updated_at
+            SELECT f.id, f.parent_id, f.name, f.owner_id, f.visibility, f.created_at, f.updated_at
             FROM folders f
-            JOIN permissions p ON p.folder_id IS NOT DISTINCT FROM f.id
-            WHERE p.user_id = $1
-              AND p.read = TRUE
+            WHERE (SELECT ep.list FROM get_effective_permission($1, f.id) ep)
               AND f.parent_id IS NOT DISTINCT FROM $2
+              AND ($3::uuid IS NULL OR folder_is_within(f.id, $3))
             ORDER BY {}
         "#,
             order_sql
         ))
         .bind(auth.user_id)
         .bind(parent)
+        .bind(auth.token_folder())
         .fetch_all(pool.inner())
         .await
     }
@@ -441,7 +485,7 @@ pub async fn get_folders_path(
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
     if !auth.admin {
-        check_permission(&mut tx, &auth, id, PermissionKind::Read).await?;
+        check_permission(&mut tx, &auth, id, PermissionKind::List).await?;
     }
     let result = sqlx::query_as!(UuidPath, "SELECT * FROM get_folder_uuid_path($1)", id)
         .fetch_all(&mut *tx)
@@ -470,7 +514,7 @@ pub async fn upload_file<'a>(
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
-    check_permission(&mut tx, &auth, data.folder, PermissionKind::Edit).await?;
+    check_permission(&mut tx, &auth, data.folder, PermissionKind::Upload).await?;
 
     let name = data
         .file
@@ -594,7 +638,9 @@ pub async fn get_all_files(pool: &State<PgPool>, auth: AuthUser) -> ApiResult<Js
             r#"
             SELECT f.id, f.folder_id, f.owner_id, f.name, f.size, f.created_at, f.updated_at
             FROM files f
-            "#
+            WHERE ($1::uuid IS NULL OR folder_is_within(f.folder_id, $1))
+            "#,
+            auth.token_folder()
         )
         .fetch_all(pool.inner())
         .await
@@ -604,11 +650,11 @@ pub async fn get_all_files(pool: &State<PgPool>, auth: AuthUser) -> ApiResult<Js
             r#"
             SELECT f.id, f.folder_id, f.owner_id, f.name, f.size, f.created_at, f.updated_at
             FROM files f
-            JOIN permissions p ON p.folder_id IS NOT DISTINCT FROM f.folder_id
-            WHERE p.user_id = $1
-              AND p.read = TRUE
+            WHERE (SELECT ep.list FROM get_effective_permission($1, f.folder_id) ep)
+              AND ($2::uuid IS NULL OR folder_is_within(f.folder_id, $2))
         "#,
-            auth.user_id
+            auth.user_id,
+            auth.token_folder()
         )
         .fetch_all(pool.inner())
         .await
@@ -646,11 +692,13 @@ pub async fn get_files(
             SELECT f.id, f.folder_id, f.owner_id, u.username AS owner_name, f.name, f.size, f.created_at, f.updated_at
             FROM files f INNER JOIN users u ON f.owner_id = u.id
             WHERE f.folder_id IS NOT DISTINCT FROM $1
+              AND ($2::uuid IS NULL OR folder_is_within(f.folder_id, $2))
             ORDER BY {}
         "#,
             order_sql
         ))
         .bind(parent)
+        .bind(auth.token_folder())
         .fetch_all(pool.inner())
         .await
     } else {
@@ -658,16 +706,16 @@ pub async fn get_files(
             r#"
             SELECT f.id, f.folder_id, f.owner_id, u.username AS owner_name, f.name, f.size, f.created_at, f.updated_at
             FROM files f INNER JOIN users u ON f.owner_id = u.id
-            INNER JOIN permissions p ON p.folder_id IS NOT DISTINCT FROM f.folder_id
-            WHERE p.user_id = $1
-              AND p.read = TRUE
+            WHERE (SELECT ep.list FROM get_effective_permission($1, f.folder_id) ep)
               AND f.folder_id IS NOT DISTINCT FROM $2
+              AND ($3::uuid IS NULL OR folder_is_within(f.folder_id, $3))
             ORDER BY {}
         "#,
             order_sql
         ))
         .bind(auth.user_id)
         .bind(parent)
+        .bind(auth.token_folder())
         .fetch_all(pool.inner())
         .await
     }
@@ -676,6 +724,70 @@ pub async fn get_files(
     Ok(Json(result))
 }
 
+// `share` is a share link token that lets people without an account download the file
+#[get("/file/<id>/content?<share>")]
+pub async fn get_file_content(
+    id: Uuid,
+    share: Option<&str>,
+    pre: Preconditions,
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>,
+    auth: AuthUser,
+) -> ApiResult<FileContent> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let file = sqlx::query!(
+        "SELECT folder_id, name, updated_at FROM files WHERE id = $1",
+        id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+    .ok_or_else(|| ApiResponse::fail(Status::NotFound, "file not found", None))?;
+
+    let share_link = match share {
+        Some(token) => Some(use_share_link(&mut tx, token, cookies, id, file.folder_id).await?),
+        None => {
+            check_read_permission(&mut tx, auth, file.folder_id).await?;
+            None
+        }
+    };
+
+    let mut path = PathBuf::from(FILES_DIR.get().unwrap());
+    path.push(&get_folder_path(&mut tx, file.folder_id).await?);
+    path.push(&file.name);
+
+    let content = serve_file(&path, file.updated_at, &pre).await?;
+    if let Some(link_id) = share_link
+        && content.counts_as_download()
+    {
+        count_share_download(&mut tx, link_id).await?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(content)
+}
+
+// rocket answers HEAD with the GET route as GET, this route keeps the method visible to
+// `Preconditions` so checking a share link doesn't use up a download
+#[head("/file/<id>/content?<share>")]
+pub async fn head_file_content(
+    id: Uuid,
+    share: Option<&str>,
+    pre: Preconditions,
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>,
+    auth: AuthUser,
+) -> ApiResult<FileContent> {
+    get_file_content(id, share, pre, pool, cookies, auth).await
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct RemoveFile {
     pub id: Uuid,
@@ -698,7 +810,7 @@ pub async fn delete_file(
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
-    check_permission(&mut tx, &auth, file.folder_id, PermissionKind::Edit).await?;
+    check_permission(&mut tx, &auth, file.folder_id, PermissionKind::Delete).await?;
 
     let mut base = PathBuf::from(FILES_DIR.get().unwrap());
     base.push(&get_folder_path(&mut tx, file.folder_id).await?);
@@ -750,7 +862,7 @@ pub async fn edit_file(data: Json<EditFile>, pool: &State<PgPool>, auth: AuthUse
         return Err(ApiResponse::fail(Status::NotFound, "file not found", None));
     };
 
-    check_permission(&mut tx, &auth, file.folder_id, PermissionKind::Edit).await?;
+    check_permission(&mut tx, &auth, file.folder_id, PermissionKind::Rename).await?;
     check_name(&data.name)?;
 
     let result = sqlx::query!(
@@ -851,8 +963,9 @@ async fn move_item(data: Item, pool: &State<PgPool>, auth: AuthUser) -> ApiResul
         }
     };
 
-    check_permission(&mut tx, &auth, current_parent, PermissionKind::Modify).await?;
-    check_permission(&mut tx, &auth, new_parent, PermissionKind::Edit).await?;
+    // the item leaves one folder and lands in another, both have to allow moving
+    check_permission(&mut tx, &auth, current_parent, PermissionKind::Move).await?;
+    check_permission(&mut tx, &auth, new_parent, PermissionKind::Move).await?;
 
     let old_folder_path = get_folder_path(&mut tx, current_parent).await?;
     let new_folder_path = get_folder_path(&mut tx, new_parent).await?;
@@ -892,7 +1005,11 @@ async fn move_item(data: Item, pool: &State<PgPool>, auth: AuthUser) -> ApiResul
                 if let sqlx::Error::Database(db_err) = &e
                     && db_err.is_unique_violation()
                 {
-                    ApiResponse::fail(Status::Conflict, "folder with this name already exists", None)
+                    ApiResponse::fail(
+                        Status::Conflict,
+                        "folder with this name already exists",
+                        None,
+                    )
                 } else {
                     ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
                 }
@@ -0,0 +1,191 @@
+use crate::auth::ldap::LdapAuthenticator;
+use crate::auth::verify_password;
+use crate::models::{ApiResponse, User};
+use crate::ApiResult;
+use rocket::http::Status;
+use sqlx::PgPool;
+
+// checks the login and password of `login`, more sources than the local password can be added here
+#[rocket::async_trait]
+pub trait Authenticator: Send + Sync {
+    // `None` when the credentials don't match, the next authenticator is tried then
+    async fn authenticate(
+        &self,
+        pool: &PgPool,
+        login: &str,
+        password: &str,
+    ) -> ApiResult<Option<User>>;
+}
+
+// bcrypt hash from `users.password`
+pub struct LocalAuthenticator;
+
+#[rocket::async_trait]
+impl Authenticator for LocalAuthenticator {
+    async fn authenticate(
+        &self,
+        pool: &PgPool,
+        login: &str,
+        password: &str,
+    ) -> ApiResult<Option<User>> {
+        let user = sqlx::query_as!(User, "SELECT * FROM users WHERE login = $1", login)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| {
+                ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+            })?;
+
+        match user {
+            Some(user) if verify_password(user.password.as_deref(), password)? => Ok(Some(user)),
+            _ => Ok(None),
+        }
+    }
+}
+
+pub struct Authenticators(Vec<Box<dyn Authenticator>>);
+
+impl Authenticators {
+    // local accounts always work last, so admins can still log in when the directory is down
+    pub fn from_env() -> Self {
+        let mut authenticators: Vec<Box<dyn Authenticator>> = Vec::new();
+        if let Some(ldap) = LdapAuthenticator::from_env() {
+            authenticators.push(Box::new(ldap));
+        }
+        authenticators.push(Box::new(LocalAuthenticator));
+        Self(authenticators)
+    }
+
+    pub async fn authenticate(
+        &self,
+        pool: &PgPool,
+        login: &str,
+        password: &str,
+    ) -> ApiResult<Option<User>> {
+        for authenticator in &self.0 {
+            if let Some(user) = authenticator.authenticate(pool, login, password).await? {
+                return Ok(Some(user));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::endpoints::insert_user;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    enum Answer {
+        NoMatch,
+        Match(User),
+        Unavailable,
+    }
+
+    // answers the same for every login and records that it was asked
+    struct Stub {
+        name: &'static str,
+        answer: Answer,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[rocket::async_trait]
+    impl Authenticator for Stub {
+        async fn authenticate(&self, _: &PgPool, _: &str, _: &str) -> ApiResult<Option<User>> {
+            self.calls.lock().unwrap().push(self.name);
+            match &self.answer {
+                Answer::NoMatch => Ok(None),
+                Answer::Match(user) => Ok(Some(user.clone())),
+                Answer::Unavailable => Err(ApiResponse::fail(
+                    Status::ServiceUnavailable,
+                    "directory is down",
+                    None,
+                )),
+            }
+        }
+    }
+
+    // the stubs in order with the local password last, like `from_env` does it
+    fn authenticators(
+        stubs: Vec<(&'static str, Answer)>,
+    ) -> (Authenticators, Arc<Mutex<Vec<&'static str>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut authenticators: Vec<Box<dyn Authenticator>> = Vec::new();
+        for (name, answer) in stubs {
+            authenticators.push(Box::new(Stub {
+                name,
+                answer,
+                calls: calls.clone(),
+            }));
+        }
+        authenticators.push(Box::new(LocalAuthenticator));
+        (Authenticators(authenticators), calls)
+    }
+
+    async fn create_user(pool: &PgPool, login: &str) -> User {
+        let mut conn = pool.acquire().await.unwrap();
+        insert_user(&mut conn, login, "password1", false)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn falls_back_in_order(pool: PgPool) {
+        let alice = create_user(&pool, "alice").await;
+        let (authenticators, calls) = authenticators(vec![
+            ("directory", Answer::NoMatch),
+            ("other", Answer::NoMatch),
+        ]);
+
+        let user = authenticators
+            .authenticate(&pool, "alice", "password1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.id, alice.id);
+        assert_eq!(*calls.lock().unwrap(), ["directory", "other"]);
+
+        let user = authenticators
+            .authenticate(&pool, "alice", "wrong password")
+            .await
+            .unwrap();
+        assert!(user.is_none());
+    }
+
+    #[sqlx::test]
+    async fn first_match_wins(pool: PgPool) {
+        create_user(&pool, "alice").await;
+        let bob = create_user(&pool, "bob").await;
+        let (authenticators, calls) = authenticators(vec![
+            ("directory", Answer::Match(bob.clone())),
+            ("other", Answer::NoMatch),
+        ]);
+
+        let user = authenticators
+            .authenticate(&pool, "alice", "password1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.id, bob.id);
+        assert_eq!(*calls.lock().unwrap(), ["directory"]);
+    }
+
+    #[sqlx::test]
+    async fn errors_stop_the_login(pool: PgPool) {
+        create_user(&pool, "alice").await;
+        let (authenticators, calls) = authenticators(vec![
+            ("directory", Answer::Unavailable),
+            ("other", Answer::NoMatch),
+        ]);
+
+        let Err((status, _)) = authenticators
+            .authenticate(&pool, "alice", "password1")
+            .await
+        else {
+            panic!("the local password was tried after an error");
+        };
+        assert_eq!(status, Status::ServiceUnavailable);
+        assert_eq!(*calls.lock().unwrap(), ["directory"]);
+    }
+}
@@ -1,15 +1,20 @@
+use crate::auth::authenticator::Authenticators;
+use crate::auth::lockout::{check_lockout, clear_login_failures, record_login_failure};
+use crate::auth::two_factor::verify_second_factor;
 use crate::auth::*;
+use crate::geoip::{GeoIp, Location};
 use crate::models::{ApiResponse, User, UserToken};
+use crate::proxy::TrustedProxies;
 use crate::ApiResult;
-use crate::REFRESH_TOKEN_TIME;
-use bcrypt::{hash, verify, DEFAULT_COST};
+use crate::{PENDING_LOGIN_TIME, REFRESH_TOKEN_TIME, SESSION_TOKEN_TIME};
+use bcrypt::{hash, DEFAULT_COST};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use rocket::http::private::cookie::Expiration;
 use rocket::http::{Cookie, CookieJar, Status};
 use rocket::serde::json::Json;
 use rocket::State;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
+use sqlx::{FromRow, PgConnection, PgPool};
 use std::net::IpAddr;
 use std::time::Duration;
 
@@ -17,53 +22,33 @@ use std::time::Duration;
 pub struct LoginData {
     pub login: String,
     pub password: String,
+    // "remember me", otherwise the session ends with the browser or after some time without activity
+    #[serde(default)]
+    pub stay_logged_in: bool,
 }
 
-#[derive(Deserialize, Default)]
-struct IpApiResponse {
-    city: Option<String>,
-    #[serde(rename = "regionName")]
-    region: Option<String>,
-    country: Option<String>,
-}
-
-// TODO: make cookies live as long as they need to
-async fn login_cookie(
+pub(crate) async fn login_cookie(
     uaip: UserAgentIp,
     pool: &State<PgPool>,
     cookies: &CookieJar<'_>,
-    user: impl Into<UserData>,
+    user: User,
     stay_logged_in: bool,
 ) -> ApiResult<()> {
-    let user = user.into();
+    ensure_active(&user)?;
     let mut tx = pool
         .begin()
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
-    let user_data = if let Some(user_ip) = uaip.client_ip {
-        let url = format!(
-            "http://ip-api.com/json/{}?fields=city,regionName,country",
-            user_ip
-        );
-        let resp = reqwest::Client::new().get(&url).send().await.map_err(|e| {
-            ApiResponse::fail(Status::InternalServerError, "request error", Some(&e))
-        })?;
-        resp.json().await.map_err(|e| {
-            ApiResponse::fail(Status::InternalServerError, "request error", Some(&e))
-        })?
-    } else {
-        IpApiResponse::default()
-    };
-
-    let refresh_token = sqlx::query_scalar!(
-        "INSERT INTO user_tokens (user_id, expires_at, user_agent, city, region, country) VALUES ($1, $2, $3, $4, $5, $6) RETURNING refresh_token",
-        user.user_id,
-        (Utc::now() + REFRESH_TOKEN_TIME),
+    let session = sqlx::query!(
+        "INSERT INTO user_tokens (user_id, expires_at, user_agent, city, region, country, stay_logged_in) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id, refresh_token",
+        user.id,
+        (Utc::now() + if stay_logged_in { REFRESH_TOKEN_TIME } else { SESSION_TOKEN_TIME }),
         uaip.user_agent,
-        user_data.city,
-        user_data.region,
-        user_data.country,
+        uaip.location.city,
+        uaip.location.region,
+        uaip.location.country,
+        stay_logged_in,
     )
         .fetch_one(&mut *tx)
         .await
@@ -72,14 +57,19 @@ async fn login_cookie(
     refresh_refresh_cookie(
         cookies,
         &RefreshTokenData {
-            user_id: user.user_id,
-            refresh_token,
+            user_id: user.id,
+            refresh_token: session.refresh_token,
             stay_logged_in,
         },
-        Expiration::Session,
+        cookie_expiration(stay_logged_in),
     )
     .await?;
-    refresh_access_cookie(cookies, &user, Expiration::Session).await?;
+    let access_data = AccessTokenData {
+        token_generation: user.token_generation,
+        session_id: Some(session.id),
+        user: user.into(),
+    };
+    refresh_access_cookie(cookies, &access_data, cookie_expiration(stay_logged_in)).await?;
 
     tx.commit()
         .await
@@ -92,6 +82,7 @@ pub async fn login(
     data: Json<LoginData>,
     uaip: UserAgentIp,
     pool: &State<PgPool>,
+    authenticators: &State<Authenticators>,
     cookies: &CookieJar<'_>,
     user: AuthUser,
 ) -> ApiResult {
@@ -111,43 +102,114 @@ pub async fn login(
         ));
     }
 
-    let user = match sqlx::query_as!(User, "SELECT * FROM users WHERE login = $1", data.login)
-        .fetch_one(pool.inner())
-        .await
-    {
-        Ok(user) => user,
-        Err(sqlx::Error::RowNotFound) => {
-            return Err(ApiResponse::fail(
-                Status::BadRequest,
-                "wrong login or password",
-                None,
-            ));
-        }
-        Err(e) => {
-            return Err(ApiResponse::fail(
-                Status::InternalServerError,
-                "database error",
-                Some(&e),
-            ));
-        }
+    check_lockout(pool.inner(), &data.login, uaip.client_ip).await?;
+
+    let Some(user) = authenticators
+        .authenticate(pool.inner(), &data.login, &data.password)
+        .await?
+    else {
+        record_login_failure(pool.inner(), &data.login, uaip.client_ip).await?;
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "wrong login or password",
+            None,
+        ));
+    };
+
+    ensure_active(&user)?;
+
+    // the session is created only after `login_two_factor` checks the code
+    if user.totp_enabled {
+        let pending = JWTData {
+            data: PendingLoginData {
+                user_id: user.id,
+                stay_logged_in: data.stay_logged_in,
+            },
+            exp: (Utc::now() + PENDING_LOGIN_TIME).timestamp() as usize,
+        };
+        create_jwt_cookie(cookies, "pending_login", &pending, Expiration::Session)?;
+        return Ok((
+            Status::Accepted,
+            ApiResponse::success_with("two-factor authentication required"),
+        ));
+    }
+
+    clear_login_failures(pool.inner(), &data.login).await?;
+    login_cookie(uaip, pool, cookies, user, data.stay_logged_in).await?;
+    Ok((Status::Ok, ApiResponse::success()))
+}
+
+#[derive(Deserialize)]
+pub struct LoginTwoFactorData {
+    pub code: String,
+}
+
+// second step of the login, `code` can also be one of the recovery codes
+#[post("/login/2fa", format = "json", data = "<data>")]
+pub async fn login_two_factor(
+    data: Json<LoginTwoFactorData>,
+    uaip: UserAgentIp,
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>,
+) -> ApiResult {
+    let Some(pending) = cookies
+        .get_private("pending_login")
+        .map(|cookie| cookie.value().to_string())
+    else {
+        return Err(ApiResponse::fail(
+            Status::Unauthorized,
+            "log in with your password first",
+            None,
+        ));
     };
 
-    if verify(&data.password, &user.password).map_err(|e| {
+    let jwt_secret = std::env::var("JWT_SECRET").map_err(|e| {
         ApiResponse::fail(
             Status::InternalServerError,
             "internal server error",
             Some(&e),
         )
-    })? {
-        login_cookie(uaip, pool, cookies, user, true).await?;
-        Ok((Status::Ok, ApiResponse::success()))
-    } else {
-        Err(ApiResponse::fail(
-            Status::BadRequest,
-            "wrong login or password",
-            None,
-        ))
+    })?;
+    let key = DecodingKey::from_secret(jwt_secret.as_bytes());
+    let pending = decode::<JWTData<PendingLoginData>>(&pending, &key, &Validation::default())
+        .map_err(|_| {
+            cookies.remove_private(Cookie::from("pending_login"));
+            ApiResponse::fail(
+                Status::Unauthorized,
+                "login expired, log in with your password again",
+                None,
+            )
+        })?
+        .claims
+        .data;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", pending.user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    // codes are guessed the same way as passwords, so they share the limits
+    check_lockout(pool.inner(), &user.login, uaip.client_ip).await?;
+    if let Err(e) = verify_second_factor(&mut tx, &user, &data.code).await {
+        if e.0 == Status::BadRequest {
+            record_login_failure(pool.inner(), &user.login, uaip.client_ip).await?;
+        }
+        return Err(e);
     }
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    clear_login_failures(pool.inner(), &user.login).await?;
+    cookies.remove_private(Cookie::from("pending_login"));
+    login_cookie(uaip, pool, cookies, user, pending.stay_logged_in).await?;
+    Ok((Status::Ok, ApiResponse::success()))
 }
 
 async fn remove_current_refresh_token(
@@ -167,7 +229,7 @@ async fn remove_current_refresh_token(
         if let Ok(refresh_data) = decode::<JWTData<RefreshTokenData>>(&refresh_token, &key, &valid)
         {
             let _ = sqlx::query!(
-                "DELETE FROM user_tokens WHERE user_id = $1 AND (refresh_token = $2 OR expires_at < NOW());",
+                "DELETE FROM user_tokens WHERE user_id = $1 AND (refresh_token = $2 OR expires_at < NOW() OR id IN (SELECT token_id FROM used_refresh_tokens WHERE refresh_token = $2));",
                 refresh_data.claims.data.user_id,
                 refresh_data.claims.data.refresh_token
             )
@@ -186,6 +248,42 @@ pub async fn logout(cookies: &CookieJar<'_>, pool: &State<PgPool>) -> Json<ApiRe
     ApiResponse::success()
 }
 
+// ends every session of the user, including the current one
+#[post("/logout/all")]
+pub async fn logout_all(
+    cookies: &CookieJar<'_>,
+    pool: &State<PgPool>,
+    user: AuthUser,
+) -> ApiResult {
+    let user = user?;
+    user.require_full_access()?;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    sqlx::query!("DELETE FROM user_tokens WHERE user_id = $1", user.user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    sqlx::query!(
+        "UPDATE users SET token_generation = token_generation + 1 WHERE id = $1",
+        user.user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    cookies.remove_private(Cookie::from("access_token"));
+    cookies.remove_private(Cookie::from("refresh_token"));
+    Ok((Status::Ok, ApiResponse::success()))
+}
+
 #[get("/user")]
 pub async fn get_user(user: AuthUser) -> ApiResult<Json<UserData>> {
     let user = user?;
@@ -194,6 +292,8 @@ pub async fn get_user(user: AuthUser) -> ApiResult<Json<UserData>> {
         login: user.login,
         username: user.username,
         admin: user.admin,
+        token: user.token,
+        session_id: user.session_id,
     }))
 }
 
@@ -252,30 +352,52 @@ pub async fn get_user_all_admin(
 
 #[derive(Serialize, Deserialize, FromRow)]
 pub struct UserTokenWithoutTheToken {
-    id: Uuid,
-    user_agent: Option<String>,
-    country: Option<String>,
-    region: Option<String>,
-    city: Option<String>,
-    expires_at: DateTime<Utc>,
-    created_at: DateTime<Utc>,
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    // the session making the request
+    pub current: bool,
 }
 
-#[get("/user/tokens")]
+pub async fn sessions_of(
+    pool: &PgPool,
+    user_id: Uuid,
+    session_id: Option<Uuid>,
+) -> ApiResult<Vec<UserTokenWithoutTheToken>> {
+    sqlx::query_as!(
+        UserTokenWithoutTheToken,
+        r#"SELECT id, user_agent, country, region, city, expires_at, created_at, COALESCE(id = $2, FALSE) AS "current!" FROM user_tokens WHERE user_id = $1 ORDER BY created_at"#,
+        user_id,
+        session_id
+    )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))
+}
+
+#[get("/user/tokens", rank = 2)]
 pub async fn get_user_tokens(
     user: AuthUser,
     pool: &State<PgPool>,
 ) -> ApiResult<Json<Vec<UserTokenWithoutTheToken>>> {
     let user = user?;
-    let user_tokens = sqlx::query_as!(
-        UserTokenWithoutTheToken,
-        "SELECT id, user_agent, country, region, city, expires_at, created_at FROM user_tokens WHERE user_id = $1 ORDER BY created_at",
-        user.user_id
-    )
-        .fetch_all(pool.inner())
-        .await
-        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
-    Ok(Json(user_tokens))
+    Ok(Json(
+        sessions_of(pool.inner(), user.user_id, user.session_id).await?,
+    ))
+}
+
+#[get("/user/tokens?<id>")]
+pub async fn get_user_tokens_admin(
+    id: Uuid,
+    admin: AuthAdminUser,
+    pool: &State<PgPool>,
+) -> ApiResult<Json<Vec<UserTokenWithoutTheToken>>> {
+    let _admin = admin?;
+    Ok(Json(sessions_of(pool.inner(), id, None).await?))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -290,6 +412,7 @@ pub async fn remove_user_token(
     pool: &State<PgPool>,
 ) -> ApiResult {
     let user = user?;
+    user.require_full_access()?;
     sqlx::query_as!(
         UserToken,
         "DELETE FROM user_tokens WHERE user_id = $1 AND id = $2",
@@ -303,10 +426,73 @@ pub async fn remove_user_token(
     Ok((Status::Ok, ApiResponse::success()))
 }
 
+// "log out other devices", the session making the request stays
+#[delete("/user/tokens/others")]
+pub async fn remove_other_user_tokens(user: AuthUser, pool: &State<PgPool>) -> ApiResult {
+    let user = user?;
+    user.require_full_access()?;
+    let removed = sqlx::query!(
+        "DELETE FROM user_tokens WHERE user_id = $1 AND id IS DISTINCT FROM $2",
+        user.user_id,
+        user.session_id
+    )
+    .execute(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    Ok((
+        Status::Ok,
+        ApiResponse::success_with(format!("removed {} sessions", removed.rows_affected())),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct AdminRemoveTokensData {
+    pub user_id: Uuid,
+    // a single session, without it all sessions of the user are removed
+    pub id: Option<Uuid>,
+}
+
+#[delete("/user/tokens/admin", format = "json", data = "<data>")]
+pub async fn remove_user_tokens_admin(
+    data: Json<AdminRemoveTokensData>,
+    admin: AuthAdminUser,
+    pool: &State<PgPool>,
+) -> ApiResult {
+    let admin = admin?;
+    let removed = sqlx::query!(
+        "DELETE FROM user_tokens WHERE user_id = $1 AND ($2::uuid IS NULL OR id = $2)",
+        data.user_id,
+        data.id
+    )
+    .execute(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    if removed.rows_affected() == 0 && data.id.is_some() {
+        return Err(ApiResponse::fail(
+            Status::NotFound,
+            "session not found",
+            None,
+        ));
+    }
+    log::info!(
+        "admin {} removed {} sessions of user {}",
+        admin.user_id,
+        removed.rows_affected(),
+        data.user_id
+    );
+    Ok((
+        Status::Ok,
+        ApiResponse::success_with(format!("removed {} sessions", removed.rows_affected())),
+    ))
+}
+
 #[derive(Deserialize)]
 pub struct ChangePasswordData {
     pub current_password: String,
     pub new_password: String,
+    // log out everywhere else, e.g. when the old password might have leaked
+    #[serde(default)]
+    pub revoke_other_sessions: bool,
 }
 
 #[patch("/user/password", format = "json", data = "<data>")]
@@ -316,6 +502,7 @@ pub async fn change_password(
     user: AuthUser,
 ) -> ApiResult {
     let user = user?;
+    user.require_full_access()?;
 
     if data.current_password.is_empty() || data.new_password.len() < 8 {
         return Err(ApiResponse::fail(
@@ -335,13 +522,7 @@ pub async fn change_password(
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
-    if !verify(&data.current_password, &password).map_err(|e| {
-        ApiResponse::fail(
-            Status::InternalServerError,
-            "internal server error",
-            Some(&e),
-        )
-    })? {
+    if !verify_password(password.as_deref(), &data.current_password)? {
         return Err(ApiResponse::fail(
             Status::BadRequest,
             "wrong current password",
@@ -366,6 +547,17 @@ pub async fn change_password(
     .await
     .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
+    if data.revoke_other_sessions {
+        sqlx::query!(
+            "DELETE FROM user_tokens WHERE user_id = $1 AND id IS DISTINCT FROM $2",
+            user.user_id,
+            user.session_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    }
+
     tx.commit()
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
@@ -387,6 +579,7 @@ pub async fn change_username(
     user: AuthUser,
 ) -> ApiResult {
     let user = user?;
+    user.require_full_access()?;
 
     if data.password.is_empty() {
         return Err(ApiResponse::fail(
@@ -406,13 +599,7 @@ pub async fn change_username(
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
-    if !verify(&data.password, &password).map_err(|e| {
-        ApiResponse::fail(
-            Status::InternalServerError,
-            "internal server error",
-            Some(&e),
-        )
-    })? {
+    if !verify_password(password.as_deref(), &data.password)? {
         return Err(ApiResponse::fail(
             Status::BadRequest,
             "wrong password",
@@ -430,12 +617,27 @@ pub async fn change_username(
     .await
     .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
+    // the cookie keeps the lifetime the session was created with
+    let stay_logged_in = sqlx::query_scalar!(
+        "SELECT stay_logged_in FROM user_tokens WHERE id = $1",
+        user.session_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+    .unwrap_or(false);
+
     tx.commit()
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
 
-    let user_data = user_data.into();
-    let _ = refresh_access_cookie(cookie, &user_data, Expiration::Session).await;
+    // the new username bumped the generation, without a new cookie the next request would refresh it
+    let access_data = AccessTokenData {
+        token_generation: user_data.token_generation,
+        session_id: user.session_id,
+        user: user_data.into(),
+    };
+    let _ = refresh_access_cookie(cookie, &access_data, cookie_expiration(stay_logged_in)).await;
 
     Ok((Status::Ok, ApiResponse::success()))
 }
@@ -446,14 +648,9 @@ pub struct CreateUserData {
     password: String,
 }
 
-#[post("/user/create", format = "json", data = "<data>")]
-pub async fn create_user(
-    data: Json<CreateUserData>,
-    pool: &State<PgPool>,
-    admin: AuthAdminUser,
-) -> ApiResult {
-    let _admin = admin?;
-    let trimmed_login = data.login.trim();
+// the rules for logins of every account, including the ones without a password, returns the trimmed login
+pub fn validate_login(login: &str) -> ApiResult<&str> {
+    let trimmed_login = login.trim();
 
     if trimmed_login.is_empty() {
         return Err(ApiResponse::fail(
@@ -470,42 +667,96 @@ pub async fn create_user(
             None,
         ));
     }
+    Ok(trimmed_login)
+}
 
-    if data.password.len() < 8 {
+// shared by every way of creating an account with a password, returns the trimmed login
+pub fn validate_new_user<'a>(login: &'a str, password: &str) -> ApiResult<&'a str> {
+    let trimmed_login = validate_login(login)?;
+
+    if password.len() < 8 {
         return Err(ApiResponse::fail(
             Status::BadRequest,
             "password mus be 8 characters long",
             None,
         ));
     }
+    Ok(trimmed_login)
+}
 
-    let mut tx = pool
-        .begin()
-        .await
-        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
-
-    let hashed_password = hash(&data.password, DEFAULT_COST).map_err(|e| {
+// the user together with its root permissions row
+pub async fn insert_user(
+    tx: &mut PgConnection,
+    login: &str,
+    password: &str,
+    admin: bool,
+) -> ApiResult<User> {
+    let hashed_password = hash(password, DEFAULT_COST).map_err(|e| {
         ApiResponse::fail(
             Status::InternalServerError,
             "internal server error",
             Some(&e),
         )
     })?;
+    insert_account(tx, login, Some(&hashed_password), admin).await
+}
 
+// accounts created by single sign-on or the directory, they can only log in through it
+pub async fn insert_user_without_password(
+    tx: &mut PgConnection,
+    login: &str,
+    admin: bool,
+) -> ApiResult<User> {
+    let login = validate_login(login)?;
+    insert_account(tx, login, None, admin).await
+}
+
+async fn insert_account(
+    tx: &mut PgConnection,
+    login: &str,
+    hashed_password: Option<&str>,
+    admin: bool,
+) -> ApiResult<User> {
     let user = sqlx::query_as!(
         User,
-        "INSERT INTO users (login, username, password) VALUES ($1, $1, $2) RETURNING *",
-        trimmed_login,
-        hashed_password
+        "INSERT INTO users (login, username, password, admin) VALUES ($1, $1, $2, $3) RETURNING *",
+        login,
+        hashed_password,
+        admin
     )
     .fetch_one(&mut *tx)
     .await
-    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    .map_err(|e| {
+        if let sqlx::Error::Database(db_err) = &e
+            && db_err.is_unique_violation()
+        {
+            return ApiResponse::fail(Status::Conflict, "login is already taken", None);
+        }
+        ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+    })?;
 
     sqlx::query!("INSERT INTO permissions (user_id) VALUES ($1)", user.id)
         .execute(&mut *tx)
         .await
         .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    Ok(user)
+}
+
+#[post("/user/create", format = "json", data = "<data>")]
+pub async fn create_user(
+    data: Json<CreateUserData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult {
+    let _admin = admin?;
+    let login = validate_new_user(&data.login, &data.password)?;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    insert_user(&mut tx, login, &data.password, false).await?;
 
     tx.commit()
         .await
@@ -516,7 +767,8 @@ pub async fn create_user(
 #[derive(Debug)]
 pub struct UserAgentIp {
     user_agent: Option<String>,
-    client_ip: Option<IpAddr>,
+    pub(crate) client_ip: Option<IpAddr>,
+    location: Location,
 }
 
 #[rocket::async_trait]
@@ -529,27 +781,23 @@ impl<'r> FromRequest<'r> for UserAgentIp {
             .get_one("User-Agent")
             .map(|s| s.to_string());
         let client_ip = extract_client_ip(request);
+        let location = request
+            .rocket()
+            .state::<GeoIp>()
+            .map(|geoip| geoip.locate(client_ip))
+            .unwrap_or_default();
         Outcome::Success(UserAgentIp {
             user_agent,
             client_ip,
+            location,
         })
     }
 }
 
 fn extract_client_ip(request: &Request<'_>) -> Option<IpAddr> {
-    let headers = request.headers();
-
-    if let Some(ip) = headers.get_one("CF-Connecting-IP")
-        && let Ok(ip) = ip.parse()
-    {
-        return Some(ip);
+    // without the state nothing is trusted and the peer address is used
+    match request.rocket().state::<TrustedProxies>() {
+        Some(proxies) => proxies.client_ip(request),
+        None => request.remote().map(|remote| remote.ip()),
     }
-
-    if let Some(ip) = headers.get_one("x-forwarded-for")
-        && let Ok(ip) = ip.parse()
-    {
-        return Some(ip);
-    }
-
-    request.client_ip()
 }
@@ -0,0 +1,339 @@
+use crate::auth::endpoints::{insert_user, login_cookie, validate_new_user, UserAgentIp};
+use crate::auth::tokens::{generate_token, hash_token};
+use crate::auth::AuthAdminUser;
+use crate::models::{ApiResponse, Invite, InvitePermission, Role, User};
+use crate::perms::endpoints::PermissionFlags;
+use crate::ApiResult;
+use chrono::{DateTime, Utc};
+use rocket::http::{CookieJar, Status};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const INVITE_PREFIX: &str = "inv_";
+
+#[derive(Serialize)]
+pub struct InviteData {
+    #[serde(flatten)]
+    pub invite: Invite,
+    pub permissions: Vec<InvitePermission>,
+}
+
+#[get("/invites")]
+pub async fn get_invites(
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult<Json<Vec<InviteData>>> {
+    let _admin = admin?;
+
+    let invites = sqlx::query_as!(Invite, "SELECT id, created_by, max_uses, uses, expires_at, created_at FROM invites ORDER BY created_at DESC")
+        .fetch_all(pool.inner())
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    let mut permissions = sqlx::query_as!(
+        InvitePermission,
+        r#"
+        SELECT id, invite_id, folder_id, role AS "role: Role",
+               list, read, upload, rename, move, delete, share, manage_permissions
+        FROM invite_permissions
+        "#
+    )
+    .fetch_all(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(
+        invites
+            .into_iter()
+            .map(|invite| InviteData {
+                permissions: permissions
+                    .extract_if(.., |p| p.invite_id == invite.id)
+                    .collect(),
+                invite,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct InvitePermissionData {
+    pub folder_id: Option<Uuid>,
+    pub role: Option<Role>,
+    #[serde(flatten)]
+    pub flags: PermissionFlags,
+}
+
+#[derive(Deserialize)]
+pub struct NewInviteData {
+    pub expires_at: DateTime<Utc>,
+    // 1 for a link meant for a single person
+    pub max_uses: i32,
+    #[serde(default)]
+    pub permissions: Vec<InvitePermissionData>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedInvite {
+    // only returned once, the link is `/invite?token=...`
+    pub token: String,
+    #[serde(flatten)]
+    pub invite: InviteData,
+}
+
+#[post("/invite", format = "json", data = "<data>")]
+pub async fn create_invite(
+    data: Json<NewInviteData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult<Json<CreatedInvite>> {
+    let admin = admin?;
+    let data = data.into_inner();
+
+    if data.max_uses < 1 {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "invite must allow at least one use",
+            None,
+        ));
+    }
+    if data.expires_at <= Utc::now() {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "expiration date must be in the future",
+            None,
+        ));
+    }
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let token = generate_token(INVITE_PREFIX);
+    let invite = sqlx::query_as!(
+        Invite,
+        r#"
+        INSERT INTO invites (token_hash, created_by, max_uses, expires_at) VALUES ($1, $2, $3, $4)
+        RETURNING id, created_by, max_uses, uses, expires_at, created_at
+        "#,
+        hash_token(&token),
+        admin.user_id,
+        data.max_uses,
+        data.expires_at
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let mut permissions = Vec::with_capacity(data.permissions.len());
+    for permission in data.permissions {
+        // like in `grant_permission` the flags come from the role when there is one
+        let flags = permission
+            .role
+            .map(PermissionFlags::from)
+            .unwrap_or(permission.flags);
+        let created = sqlx::query_as!(
+            InvitePermission,
+            r#"
+            INSERT INTO invite_permissions (invite_id, folder_id, role, list, read, upload, rename, move, delete, share, manage_permissions)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            RETURNING id, invite_id, folder_id, role AS "role: Role",
+                      list, read, upload, rename, move, delete, share, manage_permissions
+            "#,
+            invite.id,
+            permission.folder_id,
+            permission.role as Option<Role>,
+            flags.list,
+            flags.read,
+            flags.upload,
+            flags.rename,
+            flags.r#move,
+            flags.delete,
+            flags.share,
+            flags.manage_permissions,
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| {
+            if let sqlx::Error::Database(db_err) = &e {
+                if db_err.is_unique_violation() {
+                    return ApiResponse::fail(
+                        Status::Conflict,
+                        "invite can have only one permission per folder",
+                        None,
+                    );
+                }
+                if db_err.is_foreign_key_violation() {
+                    return ApiResponse::fail(Status::NotFound, "folder not found", None);
+                }
+            }
+            ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+        })?;
+        permissions.push(created);
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    log::info!(
+        "admin {} created invite {} for {} accounts",
+        admin.user_id,
+        invite.id,
+        invite.max_uses
+    );
+    Ok(Json(CreatedInvite {
+        token,
+        invite: InviteData {
+            invite,
+            permissions,
+        },
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteInviteData {
+    pub id: Uuid,
+}
+
+// accounts already created with the invite keep their permissions
+#[delete("/invite", format = "json", data = "<data>")]
+pub async fn delete_invite(
+    data: Json<DeleteInviteData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult {
+    let _admin = admin?;
+
+    let result = sqlx::query!("DELETE FROM invites WHERE id = $1", data.id)
+        .execute(pool.inner())
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    if result.rows_affected() == 0 {
+        return Err(ApiResponse::fail(
+            Status::NotFound,
+            "invite not found",
+            None,
+        ));
+    }
+    Ok((Status::Ok, ApiResponse::success()))
+}
+
+#[derive(Serialize)]
+pub struct InviteStatus {
+    pub valid: bool,
+}
+
+// lets the frontend tell about a used up link before the form is filled in
+#[get("/invite?<token>")]
+pub async fn get_invite(token: &str, pool: &State<PgPool>) -> ApiResult<Json<InviteStatus>> {
+    let valid = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM invites WHERE token_hash = $1 AND uses < max_uses AND expires_at > NOW()) AS "valid!""#,
+        hash_token(token.trim())
+    )
+    .fetch_one(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(InviteStatus { valid }))
+}
+
+#[derive(Deserialize)]
+pub struct RedeemInviteData {
+    pub token: String,
+    pub login: String,
+    // the login is used when it's empty
+    #[serde(default)]
+    pub username: String,
+    pub password: String,
+}
+
+// creates the account with the permissions of the invite and logs it in
+#[post("/invite/redeem", format = "json", data = "<data>")]
+pub async fn redeem_invite(
+    data: Json<RedeemInviteData>,
+    uaip: UserAgentIp,
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>,
+) -> ApiResult {
+    let login = validate_new_user(&data.login, &data.password)?;
+    let username = match data.username.trim() {
+        "" => login,
+        username => username,
+    };
+    if username.chars().any(char::is_control) {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "username can't contain control characters",
+            None,
+        ));
+    }
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    // the row stays locked until the end of the transaction, so the last use can't be taken twice
+    // and a failed insert gives the use back
+    let invite_id = sqlx::query_scalar!(
+        r#"
+        UPDATE invites SET uses = uses + 1
+        WHERE token_hash = $1 AND uses < max_uses AND expires_at > NOW()
+        RETURNING id
+        "#,
+        hash_token(data.token.trim())
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+    .ok_or_else(|| {
+        ApiResponse::fail(Status::Gone, "invite is invalid, expired or used up", None)
+    })?;
+
+    let user = insert_user(&mut tx, login, &data.password, false).await?;
+    let user = sqlx::query_as!(
+        User,
+        "UPDATE users SET username = $2 WHERE id = $1 RETURNING *",
+        user.id,
+        username
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+        if let sqlx::Error::Database(db_err) = &e
+            && db_err.is_unique_violation()
+        {
+            return ApiResponse::fail(Status::Conflict, "username is already taken", None);
+        }
+        ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+    })?;
+
+    // the root folder already has an empty row from `insert_user`
+    sqlx::query!(
+        r#"
+        INSERT INTO permissions (user_id, folder_id, role, list, read, upload, rename, move, delete, share, manage_permissions)
+        SELECT $1, folder_id, role, list, read, upload, rename, move, delete, share, manage_permissions
+        FROM invite_permissions WHERE invite_id = $2
+        ON CONFLICT (user_id, folder_id) WHERE user_id IS NOT NULL
+        DO UPDATE SET role = EXCLUDED.role, list = EXCLUDED.list, read = EXCLUDED.read, upload = EXCLUDED.upload,
+                      rename = EXCLUDED.rename, move = EXCLUDED.move, delete = EXCLUDED.delete,
+                      share = EXCLUDED.share, manage_permissions = EXCLUDED.manage_permissions
+        "#,
+        user.id,
+        invite_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    log::info!("user {} was created with invite {}", user.id, invite_id);
+    login_cookie(uaip, pool, cookies, user, false).await?;
+    Ok((Status::Ok, ApiResponse::success()))
+}
@@ -0,0 +1,320 @@
+use crate::auth::authenticator::Authenticator;
+use crate::auth::endpoints::insert_user_without_password;
+use crate::models::{ApiResponse, User};
+use crate::ApiResult;
+use ldap3::{dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, LdapError, Scope};
+use rocket::http::Status;
+use sqlx::PgPool;
+use std::time::Duration;
+
+const LDAP_TIMEOUT: Duration = Duration::from_secs(5);
+// `user_identities.issuer` of accounts from the directory, the subject is the directory login
+const LDAP_ISSUER: &str = "ldap";
+// resultCode of a failed simple bind
+const INVALID_CREDENTIALS: u32 = 49;
+
+pub struct LdapAuthenticator {
+    url: String,
+    starttls: bool,
+    // `{login}` is replaced with the escaped login, the first one that binds is used
+    user_dn_templates: Vec<String>,
+    admin_group_dn: Option<String>,
+}
+
+impl LdapAuthenticator {
+    pub fn from_env() -> Option<Self> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let url = var("LDAP_URL")?;
+        let user_dn_templates = var("LDAP_USER_DN_TEMPLATES")
+            .unwrap_or_default()
+            .split(';')
+            .map(str::trim)
+            .filter(|template| !template.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if user_dn_templates.is_empty() {
+            log::error!("LDAP_URL is set without LDAP_USER_DN_TEMPLATES, LDAP login is disabled");
+            return None;
+        }
+        Some(Self {
+            url,
+            starttls: var("LDAP_STARTTLS").is_some_and(|value| value == "true"),
+            user_dn_templates,
+            admin_group_dn: var("LDAP_ADMIN_GROUP_DN"),
+        })
+    }
+
+    // `None` when no template binds with the password, otherwise whether the user is in the admin group
+    async fn check_directory(
+        &self,
+        login: &str,
+        password: &str,
+    ) -> Result<Option<bool>, LdapError> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(LDAP_TIMEOUT)
+            .set_starttls(self.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        ldap3::drive!(conn);
+
+        let mut bound_dn = None;
+        for template in &self.user_dn_templates {
+            let dn = template.replace("{login}", &dn_escape(login));
+            let result = ldap
+                .with_timeout(LDAP_TIMEOUT)
+                .simple_bind(&dn, password)
+                .await?;
+            if result.rc == 0 {
+                bound_dn = Some(dn);
+                break;
+            }
+            if result.rc != INVALID_CREDENTIALS {
+                result.success()?;
+            }
+        }
+        let Some(bound_dn) = bound_dn else {
+            let _ = ldap.unbind().await;
+            return Ok(None);
+        };
+
+        let admin = match &self.admin_group_dn {
+            Some(group_dn) => {
+                let filter = format!(
+                    "(|(member={dn})(uniqueMember={dn})(memberUid={login}))",
+                    dn = ldap_escape(&bound_dn),
+                    login = ldap_escape(login)
+                );
+                let (entries, _) = ldap
+                    .with_timeout(LDAP_TIMEOUT)
+                    .search(group_dn, Scope::Base, &filter, vec!["1.1"])
+                    .await?
+                    .success()?;
+                !entries.is_empty()
+            }
+            None => false,
+        };
+        let _ = ldap.unbind().await;
+        Ok(Some(admin))
+    }
+}
+
+#[rocket::async_trait]
+impl Authenticator for LdapAuthenticator {
+    async fn authenticate(
+        &self,
+        pool: &PgPool,
+        login: &str,
+        password: &str,
+    ) -> ApiResult<Option<User>> {
+        // an empty password would be an anonymous bind that always succeeds
+        if password.is_empty() {
+            return Ok(None);
+        }
+
+        let user = sqlx::query_as!(User, "SELECT * FROM users WHERE login = $1", login)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| {
+                ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+            })?;
+        // accounts with a local password never come from the directory
+        if user.as_ref().is_some_and(|user| user.password.is_some()) {
+            return Ok(None);
+        }
+
+        let in_admin_group = match self.check_directory(login, password).await {
+            Ok(Some(in_admin_group)) => in_admin_group,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                log::warn!("LDAP login of {} failed: {}", login, e);
+                return Ok(None);
+            }
+        };
+        // the directory decides who is an admin only when the group is configured
+        let admin = self.admin_group_dn.as_ref().map(|_| in_admin_group);
+        directory_user(pool, login, user.is_some(), admin)
+            .await
+            .map(Some)
+    }
+}
+
+// the account of a login the directory accepted, created on the first login
+async fn directory_user(
+    pool: &PgPool,
+    login: &str,
+    login_taken: bool,
+    admin: Option<bool>,
+) -> ApiResult<User> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    // only accounts linked to the directory login are used, an account of the same name
+    // that came from somewhere else is never taken over
+    let linked_user_id = sqlx::query_scalar!(
+        r#"
+        UPDATE user_identities SET last_login_at = NOW()
+        WHERE issuer = $1 AND subject = $2
+        RETURNING user_id
+        "#,
+        LDAP_ISSUER,
+        login
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let user_id = match (linked_user_id, login_taken) {
+        (Some(user_id), _) => user_id,
+        (None, true) => {
+            return Err(ApiResponse::fail(
+                Status::Conflict,
+                "another account already uses this name",
+                None,
+            ));
+        }
+        (None, false) => {
+            let user = insert_user_without_password(&mut tx, login, admin.unwrap_or(false)).await?;
+            sqlx::query!(
+                "INSERT INTO user_identities (user_id, issuer, subject, last_login_at) VALUES ($1, $2, $3, NOW())",
+                user.id,
+                LDAP_ISSUER,
+                login
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+            user.id
+        }
+    };
+
+    let user = sqlx::query_as!(
+        User,
+        "UPDATE users SET admin = COALESCE($2, admin) WHERE id = $1 RETURNING *",
+        user_id,
+        admin
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::endpoints::insert_user;
+    use std::collections::HashMap;
+
+    fn from_vars(vars: &[(&str, &str)]) -> Option<LdapAuthenticator> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        LdapAuthenticator::from_vars(|name| vars.get(name).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn reads_the_configuration() {
+        let ldap = from_vars(&[
+            ("LDAP_URL", "ldap://directory"),
+            (
+                "LDAP_USER_DN_TEMPLATES",
+                "uid={login},ou=people,dc=ex; ;uid={login},ou=staff,dc=ex;",
+            ),
+            ("LDAP_STARTTLS", "true"),
+            ("LDAP_ADMIN_GROUP_DN", "cn=admins,ou=groups,dc=ex"),
+        ])
+        .unwrap();
+        assert_eq!(ldap.url, "ldap://directory");
+        assert!(ldap.starttls);
+        assert_eq!(
+            ldap.user_dn_templates,
+            ["uid={login},ou=people,dc=ex", "uid={login},ou=staff,dc=ex"]
+        );
+        assert_eq!(
+            ldap.admin_group_dn.as_deref(),
+            Some("cn=admins,ou=groups,dc=ex")
+        );
+
+        let ldap = from_vars(&[
+            ("LDAP_URL", "ldap://directory"),
+            ("LDAP_USER_DN_TEMPLATES", "uid={login},dc=ex"),
+            ("LDAP_STARTTLS", "yes"),
+        ])
+        .unwrap();
+        assert!(!ldap.starttls);
+        assert_eq!(ldap.admin_group_dn, None);
+    }
+
+    #[test]
+    fn needs_a_url_and_templates() {
+        assert!(from_vars(&[]).is_none());
+        assert!(from_vars(&[("LDAP_USER_DN_TEMPLATES", "uid={login},dc=ex")]).is_none());
+        assert!(from_vars(&[("LDAP_URL", "ldap://directory")]).is_none());
+        assert!(from_vars(&[
+            ("LDAP_URL", "ldap://directory"),
+            ("LDAP_USER_DN_TEMPLATES", " ; "),
+        ])
+        .is_none());
+    }
+
+    async fn identity_count(pool: &PgPool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM user_identities")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn first_login_creates_a_linked_account(pool: PgPool) {
+        let user = directory_user(&pool, "dave", false, Some(true))
+            .await
+            .unwrap();
+        assert_eq!(user.login, "dave");
+        assert!(user.admin);
+        assert!(user.password.is_none());
+
+        // later logins use the linked account, the group decides about admin rights every time
+        let again = directory_user(&pool, "dave", true, Some(false))
+            .await
+            .unwrap();
+        assert_eq!(again.id, user.id);
+        assert!(!again.admin);
+        // without a configured group the rights stay as they are
+        let again = directory_user(&pool, "dave", true, None).await.unwrap();
+        assert!(!again.admin);
+        assert_eq!(identity_count(&pool).await, 1);
+    }
+
+    #[sqlx::test]
+    async fn accounts_of_the_same_name_arent_taken_over(pool: PgPool) {
+        let mut conn = pool.acquire().await.unwrap();
+        let erin = insert_user_without_password(&mut conn, "erin", false)
+            .await
+            .unwrap();
+        insert_user(&mut conn, "frank", "password1", false)
+            .await
+            .unwrap();
+        drop(conn);
+
+        for login in ["erin", "frank"] {
+            let Err((status, _)) = directory_user(&pool, login, true, Some(true)).await else {
+                panic!("{} was taken over", login);
+            };
+            assert_eq!(status, Status::Conflict);
+        }
+        assert_eq!(identity_count(&pool).await, 0);
+        let admin: bool = sqlx::query_scalar("SELECT admin FROM users WHERE id = $1")
+            .bind(erin.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(!admin);
+    }
+}
@@ -0,0 +1,252 @@
+use crate::auth::AuthAdminUser;
+use crate::models::{ApiResponse, LoginFailure, LoginFailureKind};
+use crate::{ApiResult, LOGIN_FAILURE_WINDOW, LOGIN_LOCKOUT_BASE, LOGIN_LOCKOUT_MAX};
+use chrono::Utc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+// failures allowed before the lockout starts, addresses get more because of shared networks
+const ACCOUNT_FREE_FAILURES: i32 = 5;
+const IP_FREE_FAILURES: i32 = 20;
+
+fn lockout_time(kind: LoginFailureKind, failures: i32) -> Option<chrono::Duration> {
+    let free = match kind {
+        LoginFailureKind::Account => ACCOUNT_FREE_FAILURES,
+        LoginFailureKind::Ip => IP_FREE_FAILURES,
+    };
+    if failures < free {
+        return None;
+    }
+    // capped before multiplying, the maximum is reached long before that anyway
+    let exponent = (failures - free).min(16) as u32;
+    Some((LOGIN_LOCKOUT_BASE * 2i32.pow(exponent)).min(LOGIN_LOCKOUT_MAX))
+}
+
+// checked before the password so locked out attempts don't cost a bcrypt verification
+pub async fn check_lockout(pool: &PgPool, login: &str, ip: Option<IpAddr>) -> ApiResult<()> {
+    let locked_until = sqlx::query_scalar!(
+        r#"
+        SELECT MAX(locked_until) FROM login_failures
+        WHERE ((kind = 'account' AND subject = $1) OR (kind = 'ip' AND subject = $2))
+          AND locked_until > NOW()
+        "#,
+        login,
+        ip.map(|ip| ip.to_string())
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    match locked_until {
+        Some(locked_until) => Err(ApiResponse::fail(
+            Status::TooManyRequests,
+            format!(
+                "too many failed login attempts, try again in {} seconds",
+                (locked_until - Utc::now()).num_seconds().max(1)
+            ),
+            None,
+        )),
+        None => Ok(()),
+    }
+}
+
+async fn record_failure(pool: &PgPool, kind: LoginFailureKind, subject: &str) -> ApiResult<()> {
+    let failures = sqlx::query_scalar!(
+        r#"
+        INSERT INTO login_failures (kind, subject, failures)
+        VALUES ($1, $2, 1)
+        ON CONFLICT (kind, subject) DO UPDATE
+        SET failures        = CASE WHEN login_failures.last_failure_at < $3 THEN 1 ELSE login_failures.failures + 1 END,
+            last_failure_at = NOW()
+        RETURNING failures
+        "#,
+        kind as LoginFailureKind,
+        subject,
+        Utc::now() - LOGIN_FAILURE_WINDOW
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    if let Some(lockout) = lockout_time(kind, failures) {
+        sqlx::query!(
+            "UPDATE login_failures SET locked_until = $3 WHERE kind = $1 AND subject = $2",
+            kind as LoginFailureKind,
+            subject,
+            Utc::now() + lockout
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+        log::warn!(
+            "{:?} {} locked out for {} seconds after {} failed logins",
+            kind,
+            subject,
+            lockout.num_seconds(),
+            failures
+        );
+    }
+    Ok(())
+}
+
+pub async fn record_login_failure(pool: &PgPool, login: &str, ip: Option<IpAddr>) -> ApiResult<()> {
+    record_failure(pool, LoginFailureKind::Account, login).await?;
+    if let Some(ip) = ip {
+        record_failure(pool, LoginFailureKind::Ip, &ip.to_string()).await?;
+    }
+
+    // we can ignore error because if it doesn't remove now it can later
+    let _ = sqlx::query!(
+        "DELETE FROM login_failures WHERE last_failure_at < $1 AND (locked_until IS NULL OR locked_until < NOW())",
+        Utc::now() - LOGIN_FAILURE_WINDOW
+    )
+    .execute(pool)
+    .await;
+    Ok(())
+}
+
+// a successful login only clears the account, the address keeps counting until the window passes
+pub async fn clear_login_failures(pool: &PgPool, login: &str) -> ApiResult<()> {
+    sqlx::query!(
+        "DELETE FROM login_failures WHERE kind = 'account' AND subject = $1",
+        login
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    Ok(())
+}
+
+#[get("/user/lockouts")]
+pub async fn get_lockouts(
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult<Json<Vec<LoginFailure>>> {
+    let _admin = admin?;
+    let lockouts = sqlx::query_as!(
+        LoginFailure,
+        r#"
+        SELECT kind AS "kind: LoginFailureKind", subject, failures, locked_until, last_failure_at
+        FROM login_failures
+        WHERE locked_until > NOW()
+        ORDER BY locked_until DESC
+        "#
+    )
+    .fetch_all(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    Ok(Json(lockouts))
+}
+
+#[derive(Deserialize)]
+pub struct UnlockData {
+    // the account to unlock
+    pub id: Option<Uuid>,
+    // or an address
+    pub ip: Option<IpAddr>,
+}
+
+#[delete("/user/lockout", format = "json", data = "<data>")]
+pub async fn unlock(
+    data: Json<UnlockData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult {
+    let admin = admin?;
+
+    let (kind, subject) = match (data.id, data.ip) {
+        (Some(id), None) => {
+            let login = sqlx::query_scalar!("SELECT login FROM users WHERE id = $1", id)
+                .fetch_optional(pool.inner())
+                .await
+                .map_err(|e| {
+                    ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+                })?
+                .ok_or_else(|| ApiResponse::fail(Status::NotFound, "user not found", None))?;
+            (LoginFailureKind::Account, login)
+        }
+        (None, Some(ip)) => (LoginFailureKind::Ip, ip.to_string()),
+        _ => {
+            return Err(ApiResponse::fail(
+                Status::BadRequest,
+                "either id or ip must be provided",
+                None,
+            ));
+        }
+    };
+
+    sqlx::query!(
+        "DELETE FROM login_failures WHERE kind = $1 AND subject = $2",
+        kind as LoginFailureKind,
+        subject
+    )
+    .execute(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    log::info!("admin {} unlocked {:?} {}", admin.user_id, kind, subject);
+    Ok((Status::Ok, ApiResponse::success()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_failures_before_the_lockout() {
+        for failures in 0..ACCOUNT_FREE_FAILURES {
+            assert_eq!(lockout_time(LoginFailureKind::Account, failures), None);
+        }
+        for failures in 0..IP_FREE_FAILURES {
+            assert_eq!(lockout_time(LoginFailureKind::Ip, failures), None);
+        }
+    }
+
+    #[test]
+    fn lockout_doubles_with_every_failure() {
+        let account = |failures| lockout_time(LoginFailureKind::Account, failures);
+        assert_eq!(account(ACCOUNT_FREE_FAILURES), Some(LOGIN_LOCKOUT_BASE));
+        assert_eq!(
+            account(ACCOUNT_FREE_FAILURES + 1),
+            Some(LOGIN_LOCKOUT_BASE * 2)
+        );
+        assert_eq!(
+            account(ACCOUNT_FREE_FAILURES + 3),
+            Some(LOGIN_LOCKOUT_BASE * 8)
+        );
+        assert_eq!(
+            lockout_time(LoginFailureKind::Ip, IP_FREE_FAILURES + 2),
+            Some(LOGIN_LOCKOUT_BASE * 4)
+        );
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        let account = |failures| lockout_time(LoginFailureKind::Account, failures);
+        assert_eq!(account(ACCOUNT_FREE_FAILURES + 16), Some(LOGIN_LOCKOUT_MAX));
+        assert_eq!(account(i32::MAX), Some(LOGIN_LOCKOUT_MAX));
+    }
+
+    #[sqlx::test]
+    async fn locks_the_account_until_a_successful_login(pool: PgPool) {
+        let ip = Some("203.0.113.7".parse().unwrap());
+        for _ in 0..ACCOUNT_FREE_FAILURES - 1 {
+            record_login_failure(&pool, "alice", ip).await.unwrap();
+        }
+        assert!(check_lockout(&pool, "alice", ip).await.is_ok());
+
+        record_login_failure(&pool, "alice", ip).await.unwrap();
+        let (status, _) = check_lockout(&pool, "alice", ip).await.unwrap_err();
+        assert_eq!(status, Status::TooManyRequests);
+        // other accounts from the same address aren't locked yet
+        assert!(check_lockout(&pool, "bob", ip).await.is_ok());
+
+        clear_login_failures(&pool, "alice").await.unwrap();
+        assert!(check_lockout(&pool, "alice", ip).await.is_ok());
+    }
+}
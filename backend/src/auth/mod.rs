@@ -1,19 +1,33 @@
-use crate::models::{ApiResponse, User};
-use crate::{ApiResult, ACCESS_TOKEN_TIME, REFRESH_TOKEN_TIME};
+use crate::auth::tokens::{user_from_access_token, TokenAccess};
+use crate::auth::two_factor::is_missing_required_2fa;
+use crate::models::{ApiResponse, TokenScope, User};
+use crate::{
+    ApiResult, ACCESS_TOKEN_TIME, REFRESH_REUSE_GRACE, REFRESH_TOKEN_TIME, SESSION_TOKEN_TIME,
+};
 use chrono::Utc;
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use rocket::http::private::cookie::Expiration;
 use rocket::http::{Cookie, CookieJar, SameSite, Status};
-use rocket::outcome::try_outcome;
 use rocket::request::{FromRequest, Outcome};
 use rocket::serde::json::Json;
+use rocket::time::{self, OffsetDateTime};
 use rocket::{Request, State};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use std::borrow::Cow;
 use uuid::Uuid;
 
+pub mod authenticator;
 pub mod endpoints;
+pub mod invites;
+pub mod ldap;
+pub mod lockout;
+pub mod oidc;
+pub mod passkeys;
+pub mod password_reset;
+pub mod tokens;
+pub mod two_factor;
+pub mod users;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JWTData<T> {
@@ -28,12 +42,64 @@ pub struct RefreshTokenData {
     pub stay_logged_in: bool,
 }
 
+// password was correct but the second factor is still missing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingLoginData {
+    pub user_id: Uuid,
+    pub stay_logged_in: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserData {
     pub user_id: Uuid,
     pub login: String,
     pub username: String,
     pub admin: bool,
+    #[serde(skip)]
+    pub token: Option<TokenAccess>,
+    // row in `user_tokens` of the cookie login
+    #[serde(skip)]
+    pub session_id: Option<Uuid>,
+}
+
+// payload of the access cookie, the session and the generation are checked on every request
+// so revoked sessions and changed accounts don't have to wait for the token to expire
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessTokenData {
+    #[serde(flatten)]
+    pub user: UserData,
+    // cookies issued before sessions were checked don't have these and get refreshed
+    #[serde(default)]
+    pub session_id: Option<Uuid>,
+    #[serde(default)]
+    pub token_generation: i32,
+}
+
+impl UserData {
+    // logged in with cookies or with a token that has the admin scope
+    pub fn has_full_access(&self) -> bool {
+        self.token
+            .as_ref()
+            .is_none_or(|token| token.scope == TokenScope::Admin)
+    }
+
+    // listings only show what's inside the folder the token is limited to
+    pub fn token_folder(&self) -> Option<Uuid> {
+        self.token.as_ref().and_then(|token| token.folder_id)
+    }
+
+    // for account settings and everything else that isn't covered by the scope of a token
+    pub fn require_full_access(&self) -> ApiResult<()> {
+        if self.has_full_access() {
+            Ok(())
+        } else {
+            Err(ApiResponse::fail(
+                Status::Forbidden,
+                "access token scope doesn't allow this",
+                None,
+            ))
+        }
+    }
 }
 
 impl From<AdminData> for UserData {
@@ -43,6 +109,8 @@ impl From<AdminData> for UserData {
             login: value.login,
             username: value.username,
             admin: true,
+            token: None,
+            session_id: None,
         }
     }
 }
@@ -54,6 +122,8 @@ impl From<User> for UserData {
             login: value.login,
             username: value.username,
             admin: value.admin,
+            token: None,
+            session_id: None,
         }
     }
 }
@@ -95,7 +165,7 @@ impl TryFrom<UserData> for AdminData {
 pub type AuthUser = ApiResult<UserData>;
 pub type AuthAdminUser = ApiResult<AdminData>;
 
-fn create_jwt_cookie<T: Serialize, Str: Into<Cow<'static, str>>>(
+pub(crate) fn create_jwt_cookie<T: Serialize, Str: Into<Cow<'static, str>>>(
     cookies: &CookieJar<'_>,
     name: Str,
     data: &JWTData<T>,
@@ -130,13 +200,24 @@ fn create_jwt_cookie<T: Serialize, Str: Into<Cow<'static, str>>>(
     Ok(())
 }
 
+// cookies of persistent logins outlive the browser, the rest is gone when it's closed
+fn cookie_expiration(stay_logged_in: bool) -> Expiration {
+    if stay_logged_in {
+        Expiration::DateTime(
+            OffsetDateTime::now_utc() + time::Duration::seconds(REFRESH_TOKEN_TIME.num_seconds()),
+        )
+    } else {
+        Expiration::Session
+    }
+}
+
 async fn refresh_access_cookie(
     cookies: &CookieJar<'_>,
-    user: &UserData,
+    access_data: &AccessTokenData,
     expiration: Expiration,
 ) -> ApiResult<()> {
     let user_token = JWTData {
-        data: user,
+        data: access_data,
         exp: (Utc::now() + ACCESS_TOKEN_TIME).timestamp() as usize,
     };
     create_jwt_cookie(cookies, "access_token", &user_token, expiration)
@@ -154,9 +235,207 @@ async fn refresh_refresh_cookie(
     create_jwt_cookie(cookies, "refresh_token", &user_token, expiration)
 }
 
+// checked on every login and refresh, their sessions are also removed when they get deactivated
+pub fn ensure_active(user: &User) -> ApiResult<()> {
+    if user.deactivated {
+        Err(ApiResponse::fail(
+            Status::Forbidden,
+            "this account is deactivated",
+            None,
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// users from single sign-on don't have a password, for them every password is wrong
+pub fn verify_password(hashed: Option<&str>, password: &str) -> ApiResult<bool> {
+    let Some(hashed) = hashed else {
+        return Ok(false);
+    };
+    bcrypt::verify(password, hashed).map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })
+}
+
+enum RefreshOutcome {
+    // the session continues with this refresh token
+    Rotated {
+        session_id: Uuid,
+        refresh_token: Uuid,
+    },
+    // another request has just rotated the same token, its response carries the new one
+    AlreadyRotated {
+        session_id: Uuid,
+    },
+    Invalid,
+}
+
+// every refresh swaps the token and pushes the expiration forward, presenting a token
+// that was already swapped revokes the whole session because someone else must have a copy of it
+async fn rotate_refresh_token(
+    pool: &PgPool,
+    refresh_data: &RefreshTokenData,
+) -> ApiResult<RefreshOutcome> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let rotated = sqlx::query!(
+        r#"
+        WITH rotated AS (
+            UPDATE user_tokens
+            SET refresh_token = gen_random_uuid(),
+                expires_at    = CASE WHEN stay_logged_in THEN $3::timestamptz ELSE $5::timestamptz END
+            WHERE user_id = $1 AND refresh_token = $2 AND expires_at > NOW()
+            RETURNING id, refresh_token
+        ), used AS (
+            INSERT INTO used_refresh_tokens (refresh_token, token_id)
+            SELECT $2, id FROM rotated
+        ), pruned AS (
+            -- jwt of tokens used this long ago has expired, so they can't be presented anymore
+            DELETE FROM used_refresh_tokens
+            WHERE token_id IN (SELECT id FROM rotated) AND used_at < $4
+        )
+        SELECT id AS "id!", refresh_token AS "refresh_token!" FROM rotated
+        "#,
+        refresh_data.user_id,
+        refresh_data.refresh_token,
+        Utc::now() + REFRESH_TOKEN_TIME,
+        Utc::now() - REFRESH_TOKEN_TIME,
+        Utc::now() + SESSION_TOKEN_TIME,
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let outcome = match rotated {
+        Some(rotated) => RefreshOutcome::Rotated {
+            session_id: rotated.id,
+            refresh_token: rotated.refresh_token,
+        },
+        None => {
+            let reused = sqlx::query!(
+                r#"
+                SELECT t.id, u.used_at
+                FROM used_refresh_tokens u
+                INNER JOIN user_tokens t ON t.id = u.token_id
+                WHERE u.refresh_token = $2 AND t.user_id = $1 AND t.expires_at > NOW()
+                "#,
+                refresh_data.user_id,
+                refresh_data.refresh_token
+            )
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| {
+                ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+            })?;
+
+            match reused {
+                Some(reused) if reused.used_at > Utc::now() - REFRESH_REUSE_GRACE => {
+                    RefreshOutcome::AlreadyRotated {
+                        session_id: reused.id,
+                    }
+                }
+                Some(reused) => {
+                    sqlx::query!("DELETE FROM user_tokens WHERE id = $1", reused.id)
+                        .execute(&mut *tx)
+                        .await
+                        .map_err(|e| {
+                            ApiResponse::fail(
+                                Status::InternalServerError,
+                                "database error",
+                                Some(&e),
+                            )
+                        })?;
+                    log::warn!(
+                        "refresh token of user {} was used again, session {} was revoked",
+                        refresh_data.user_id,
+                        reused.id
+                    );
+                    RefreshOutcome::Invalid
+                }
+                None => RefreshOutcome::Invalid,
+            }
+        }
+    };
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    Ok(outcome)
+}
+
+// `None` means the account has changed since the token was issued and it has to be refreshed
+async fn check_access_token(
+    pool: &PgPool,
+    cookies: &CookieJar<'_>,
+    access_data: AccessTokenData,
+) -> ApiResult<Option<UserData>> {
+    let Some(session_id) = access_data.session_id else {
+        return Ok(None);
+    };
+    let token_generation = sqlx::query_scalar!(
+        r#"
+        SELECT u.token_generation
+        FROM user_tokens t
+        INNER JOIN users u ON u.id = t.user_id
+        WHERE t.id = $1 AND t.user_id = $2 AND t.expires_at > NOW() AND NOT u.deactivated
+        "#,
+        session_id,
+        access_data.user.user_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    match token_generation {
+        Some(generation) if generation == access_data.token_generation => Ok(Some(UserData {
+            session_id: Some(session_id),
+            ..access_data.user
+        })),
+        Some(_) => Ok(None),
+        // the session was revoked, its refresh token is gone as well
+        None => {
+            cookies.remove_private(Cookie::from("access_token"));
+            cookies.remove_private(Cookie::from("refresh_token"));
+            Err(ApiResponse::fail(
+                Status::Unauthorized,
+                "you are not authenticated",
+                None,
+            ))
+        }
+    }
+}
+
+fn bearer_token<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
 async fn from_request_user_data(
     request: &Request<'_>,
 ) -> Result<UserData, (Status, Json<ApiResponse>)> {
+    // scripts use access tokens instead of cookies
+    if let Some(token) = bearer_token(request) {
+        return match request.guard::<&State<PgPool>>().await {
+            Outcome::Success(pool) => user_from_access_token(pool, token).await,
+            Outcome::Error(_) | Outcome::Forward(_) => Err(ApiResponse::fail(
+                Status::InternalServerError,
+                "internal server error",
+                None,
+            )),
+        };
+    }
+
     let cookies = request.cookies();
     let access_token = cookies
         .get_private("access_token")
@@ -177,9 +456,24 @@ async fn from_request_user_data(
         ApiResponse::fail(Status::InternalServerError, "internal server error", None)
     })?;
     let key = DecodingKey::from_secret(jwt_secret.as_bytes());
+    let pool = match request.guard::<&State<PgPool>>().await {
+        Outcome::Success(pool) => pool,
+        Outcome::Error(_) | Outcome::Forward(_) => {
+            return Err(ApiResponse::fail(
+                Status::InternalServerError,
+                "internal server error",
+                None,
+            ));
+        }
+    };
+
     if let Some(access_token) = access_token {
-        match decode::<JWTData<UserData>>(&access_token, &key, &Validation::default()) {
-            Ok(v) => return Ok(v.claims.data),
+        match decode::<JWTData<AccessTokenData>>(&access_token, &key, &Validation::default()) {
+            Ok(v) => {
+                if let Some(user) = check_access_token(pool, cookies, v.claims.data).await? {
+                    return Ok(user);
+                }
+            }
             // no need to return anything and the code can continue, if token expired it can be refreshed because the refresh token exists
             Err(e) if e.kind() == &jsonwebtoken::errors::ErrorKind::ExpiredSignature => {}
             // the difference with these next two patterns is that second one logs errors to console
@@ -200,7 +494,6 @@ async fn from_request_user_data(
         }
     };
 
-    // TODO: refresh refresh token some time (7-14 days) before expiration
     // if we are here it means the token has expired or cookie for access doesn't exist
     let refresh_data = match decode::<JWTData<RefreshTokenData>>(
         &refresh_token.unwrap(),
@@ -228,43 +521,30 @@ async fn from_request_user_data(
         }
     };
 
-    let pool = match request.guard::<&State<PgPool>>().await {
-        Outcome::Success(pool) => pool,
-        Outcome::Error(_) | Outcome::Forward(_) => {
+    let (session_id, rotated_token) = match rotate_refresh_token(pool, &refresh_data).await? {
+        RefreshOutcome::Rotated {
+            session_id,
+            refresh_token,
+        } => (session_id, Some(refresh_token)),
+        RefreshOutcome::AlreadyRotated { session_id } => (session_id, None),
+        RefreshOutcome::Invalid => {
+            // we can ignore error because if it doesn't remove now it can later
+            let _ = sqlx::query!(
+                "DELETE FROM user_tokens WHERE user_id = $1 AND expires_at < NOW();",
+                refresh_data.user_id
+            )
+            .execute(pool.inner())
+            .await;
+            cookies.remove_private(Cookie::from("access_token"));
+            cookies.remove_private(Cookie::from("refresh_token"));
             return Err(ApiResponse::fail(
-                Status::InternalServerError,
-                "internal server error",
+                Status::Unauthorized,
+                "you are not authenticated",
                 None,
             ));
         }
     };
 
-    let expires_at = sqlx::query_scalar!(
-        "SELECT expires_at FROM user_tokens WHERE user_id = $1 AND refresh_token = $2 ORDER BY created_at DESC LIMIT 1",
-        refresh_data.user_id,
-        refresh_data.refresh_token
-    )
-        .fetch_optional(pool.inner())
-        .await
-        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
-
-    if expires_at.is_none() || expires_at.unwrap() < Utc::now() {
-        // we can ignore error because if it doesn't remove now it can later
-        let _ = sqlx::query!(
-            "DELETE FROM user_tokens WHERE user_id = $1 AND expires_at < NOW();",
-            refresh_data.user_id
-        )
-        .execute(pool.inner())
-        .await;
-        cookies.remove_private(Cookie::from("access_token"));
-        cookies.remove_private(Cookie::from("refresh_token"));
-        return Err(ApiResponse::fail(
-            Status::Unauthorized,
-            "you are not authenticated",
-            None,
-        ));
-    }
-
     let user = sqlx::query_as!(
         User,
         "SELECT * FROM users WHERE id = $1",
@@ -273,25 +553,68 @@ async fn from_request_user_data(
     .fetch_one(pool.inner())
     .await
     .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
-    let user_data: UserData = user.into();
+    if let Err(e) = ensure_active(&user) {
+        cookies.remove_private(Cookie::from("access_token"));
+        cookies.remove_private(Cookie::from("refresh_token"));
+        return Err(e);
+    }
+    let access_data = AccessTokenData {
+        token_generation: user.token_generation,
+        session_id: Some(session_id),
+        user: UserData {
+            session_id: Some(session_id),
+            ..user.into()
+        },
+    };
 
-    refresh_access_cookie(cookies, &user_data, Expiration::Session).await?;
+    let expiration = cookie_expiration(refresh_data.stay_logged_in);
+    if let Some(refresh_token) = rotated_token {
+        let refresh_data = RefreshTokenData {
+            refresh_token,
+            ..refresh_data
+        };
+        refresh_refresh_cookie(cookies, &refresh_data, expiration).await?;
+    }
+    refresh_access_cookie(cookies, &access_data, expiration).await?;
 
-    Ok(user_data)
+    Ok(access_data.user)
 }
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for AdminData {
     type Error = (Status, Json<ApiResponse>);
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserData>().await);
-        if user.admin {
-            Outcome::Success(user.try_into().unwrap())
-        } else {
-            Outcome::Error((
+        // the account itself, the `UserData` guard takes admin rights away while 2FA is missing
+        let user = match cached_user_data(request).await {
+            Ok(user) => user.clone(),
+            Err(e) => return Outcome::Error((e.0, e.clone())),
+        };
+        if !user.admin || !user.has_full_access() {
+            return Outcome::Error((
                 Status::Unauthorized,
                 ApiResponse::fail(Status::Unauthorized, "you are not an admin", None),
-            ))
+            ));
+        }
+
+        let missing_2fa = match request.guard::<&State<PgPool>>().await {
+            Outcome::Success(pool) => is_missing_required_2fa(pool, user.user_id).await,
+            Outcome::Error(_) | Outcome::Forward(_) => Err(ApiResponse::fail(
+                Status::InternalServerError,
+                "internal server error",
+                None,
+            )),
+        };
+        match missing_2fa {
+            Ok(false) => Outcome::Success(user.try_into().unwrap()),
+            Ok(true) => Outcome::Error((
+                Status::Forbidden,
+                ApiResponse::fail(
+                    Status::Forbidden,
+                    "two-factor authentication is required for admin accounts",
+                    None,
+                ),
+            )),
+            Err(e) => Outcome::Error((e.0, e)),
         }
     }
 }
@@ -300,13 +623,139 @@ impl<'r> FromRequest<'r> for AdminData {
 impl<'r> FromRequest<'r> for UserData {
     type Error = (Status, Json<ApiResponse>);
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let request_user = request
-            .local_cache_async(async { from_request_user_data(request).await })
-            .await;
+        let user = match cached_user_data(request).await {
+            Ok(user) => user.clone(),
+            Err(e) => return Outcome::Error((e.0, e.clone())),
+        };
+        if !user.admin {
+            return Outcome::Success(user);
+        }
 
-        match request_user {
-            Ok(v) => Outcome::Success(v.clone()),
-            Err(e) => Outcome::Error((e.0, e.clone())),
+        // admins skip permission checks everywhere, while they still have to set up the required 2FA
+        // they only get what was granted to them like any other user
+        let missing_2fa = match request.guard::<&State<PgPool>>().await {
+            Outcome::Success(pool) => is_missing_required_2fa(pool, user.user_id).await,
+            Outcome::Error(_) | Outcome::Forward(_) => Err(ApiResponse::fail(
+                Status::InternalServerError,
+                "internal server error",
+                None,
+            )),
+        };
+        match missing_2fa {
+            Ok(false) => Outcome::Success(user),
+            Ok(true) => Outcome::Success(UserData {
+                admin: false,
+                ..user
+            }),
+            Err(e) => Outcome::Error((e.0, e)),
         }
     }
 }
+
+async fn cached_user_data<'r>(request: &'r Request<'_>) -> &'r ApiResult<UserData> {
+    request
+        .local_cache_async(async { from_request_user_data(request).await })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::endpoints::insert_user;
+
+    async fn new_session(pool: &PgPool) -> RefreshTokenData {
+        let mut conn = pool.acquire().await.unwrap();
+        let user = insert_user(&mut conn, "alice", "password1", false)
+            .await
+            .unwrap();
+        let refresh_token = sqlx::query_scalar!(
+            "INSERT INTO user_tokens (user_id, expires_at, stay_logged_in) VALUES ($1, $2, FALSE) RETURNING refresh_token",
+            user.id,
+            Utc::now() + SESSION_TOKEN_TIME
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap();
+        RefreshTokenData {
+            user_id: user.id,
+            refresh_token,
+            stay_logged_in: false,
+        }
+    }
+
+    async fn rotate(pool: &PgPool, refresh_data: &RefreshTokenData) -> RefreshOutcome {
+        rotate_refresh_token(pool, refresh_data).await.unwrap()
+    }
+
+    async fn session_count(pool: &PgPool) -> i64 {
+        sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM user_tokens"#)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn refresh_swaps_the_token(pool: PgPool) {
+        let old = new_session(&pool).await;
+        let RefreshOutcome::Rotated { refresh_token, .. } = rotate(&pool, &old).await else {
+            panic!("the first refresh didn't rotate the token");
+        };
+        assert_ne!(refresh_token, old.refresh_token);
+
+        let new = RefreshTokenData {
+            refresh_token,
+            ..old
+        };
+        assert!(matches!(
+            rotate(&pool, &new).await,
+            RefreshOutcome::Rotated { .. }
+        ));
+    }
+
+    #[sqlx::test]
+    async fn concurrent_refresh_is_allowed_within_the_grace_period(pool: PgPool) {
+        let old = new_session(&pool).await;
+        let RefreshOutcome::Rotated { session_id, .. } = rotate(&pool, &old).await else {
+            panic!("the first refresh didn't rotate the token");
+        };
+        assert!(matches!(
+            rotate(&pool, &old).await,
+            RefreshOutcome::AlreadyRotated { session_id: id } if id == session_id
+        ));
+        assert_eq!(session_count(&pool).await, 1);
+    }
+
+    #[sqlx::test]
+    async fn reused_token_revokes_the_session(pool: PgPool) {
+        let old = new_session(&pool).await;
+        let RefreshOutcome::Rotated { refresh_token, .. } = rotate(&pool, &old).await else {
+            panic!("the first refresh didn't rotate the token");
+        };
+        sqlx::query!(
+            "UPDATE used_refresh_tokens SET used_at = $1",
+            Utc::now() - REFRESH_REUSE_GRACE * 2
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert!(matches!(rotate(&pool, &old).await, RefreshOutcome::Invalid));
+        assert_eq!(session_count(&pool).await, 0);
+        // the token the legitimate client got is gone with the session
+        let new = RefreshTokenData {
+            refresh_token,
+            ..old
+        };
+        assert!(matches!(rotate(&pool, &new).await, RefreshOutcome::Invalid));
+    }
+
+    #[sqlx::test]
+    async fn expired_session_isnt_refreshed(pool: PgPool) {
+        let old = new_session(&pool).await;
+        sqlx::query!("UPDATE user_tokens SET expires_at = NOW() - INTERVAL '1 second'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(rotate(&pool, &old).await, RefreshOutcome::Invalid));
+    }
+}
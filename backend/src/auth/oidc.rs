@@ -0,0 +1,529 @@
+use crate::auth::endpoints::{insert_user_without_password, login_cookie, UserAgentIp};
+use crate::auth::{create_jwt_cookie, AuthUser, JWTData};
+use crate::models::{ApiResponse, User};
+use crate::{ApiResult, OIDC_METADATA_TIME, PENDING_LOGIN_TIME};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, DecodingKey, Validation};
+use openidconnect::core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata};
+use openidconnect::{
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointMaybeSet, EndpointNotSet,
+    EndpointSet, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
+    TokenResponse,
+};
+use rocket::http::private::cookie::Expiration;
+use rocket::http::{Cookie, CookieJar, RawStr, Status};
+use rocket::response::Redirect;
+use rocket::serde::json::Value;
+use rocket::tokio::sync::RwLock;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+type OidcClient = CoreClient<
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointMaybeSet,
+    EndpointMaybeSet,
+>;
+
+struct OidcConfig {
+    issuer_url: String,
+    client_id: String,
+    client_secret: Option<String>,
+    // the callback endpoint below as the provider sees it
+    redirect_url: String,
+    auto_provision: bool,
+    admin_claim: Option<String>,
+    admin_value: Option<String>,
+}
+
+fn oidc_config() -> ApiResult<OidcConfig> {
+    let not_configured =
+        |_| ApiResponse::fail(Status::NotFound, "single sign-on is not configured", None);
+    Ok(OidcConfig {
+        issuer_url: std::env::var("OIDC_ISSUER_URL").map_err(not_configured)?,
+        client_id: std::env::var("OIDC_CLIENT_ID").map_err(not_configured)?,
+        client_secret: std::env::var("OIDC_CLIENT_SECRET").ok(),
+        redirect_url: std::env::var("OIDC_REDIRECT_URL").map_err(not_configured)?,
+        auto_provision: std::env::var("OIDC_AUTO_PROVISION").is_ok_and(|value| value == "true"),
+        admin_claim: std::env::var("OIDC_ADMIN_CLAIM").ok(),
+        admin_value: std::env::var("OIDC_ADMIN_VALUE").ok(),
+    })
+}
+
+fn http_client() -> ApiResult<reqwest::Client> {
+    // following redirects would let the provider make us request anything
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(|e| {
+            ApiResponse::fail(
+                Status::InternalServerError,
+                "internal server error",
+                Some(&e),
+            )
+        })
+}
+
+// discovery is a request to the provider, so the result is kept for a while instead of
+// asking again on every login and callback
+#[derive(Default)]
+pub struct OidcMetadata(RwLock<Option<(DateTime<Utc>, CoreProviderMetadata)>>);
+
+impl OidcMetadata {
+    async fn get(
+        &self,
+        issuer_url: IssuerUrl,
+        http: &reqwest::Client,
+    ) -> ApiResult<CoreProviderMetadata> {
+        if let Some((discovered_at, metadata)) = &*self.0.read().await
+            && *discovered_at + OIDC_METADATA_TIME > Utc::now()
+        {
+            return Ok(metadata.clone());
+        }
+
+        let metadata = CoreProviderMetadata::discover_async(issuer_url, http)
+            .await
+            .map_err(|e| {
+                ApiResponse::fail(
+                    Status::BadGateway,
+                    "identity provider is not available",
+                    Some(&e),
+                )
+            })?;
+        *self.0.write().await = Some((Utc::now(), metadata.clone()));
+        Ok(metadata)
+    }
+}
+
+async fn oidc_client(
+    config: &OidcConfig,
+    http: &reqwest::Client,
+    cache: &OidcMetadata,
+) -> ApiResult<OidcClient> {
+    let issuer_url = IssuerUrl::new(config.issuer_url.clone()).map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })?;
+    let redirect_url = RedirectUrl::new(config.redirect_url.clone()).map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })?;
+    let metadata = cache.get(issuer_url, http).await?;
+
+    Ok(CoreClient::from_provider_metadata(
+        metadata,
+        ClientId::new(config.client_id.clone()),
+        config.client_secret.clone().map(ClientSecret::new),
+    )
+    .set_redirect_uri(redirect_url))
+}
+
+// kept in a private cookie between the redirect to the provider and the callback
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OidcLoginData {
+    csrf_token: String,
+    nonce: String,
+    pkce_verifier: String,
+    // set when a logged-in user links the provider account to their own
+    link_user_id: Option<Uuid>,
+    stay_logged_in: bool,
+}
+
+#[get("/login/oidc?<stay_logged_in>")]
+pub async fn oidc_login(
+    stay_logged_in: Option<bool>,
+    metadata: &State<OidcMetadata>,
+    cookies: &CookieJar<'_>,
+    user: AuthUser,
+) -> ApiResult<Redirect> {
+    let link_user_id = match user {
+        Ok(user) => {
+            user.require_full_access()?;
+            Some(user.user_id)
+        }
+        Err(_) => None,
+    };
+    let config = oidc_config()?;
+    let client = oidc_client(&config, &http_client()?, metadata).await?;
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (auth_url, csrf_token, nonce) = client
+        .authorize_url(
+            CoreAuthenticationFlow::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        .add_scope(Scope::new("profile".to_string()))
+        .add_scope(Scope::new("email".to_string()))
+        .set_pkce_challenge(pkce_challenge)
+        .url();
+
+    let data = JWTData {
+        data: OidcLoginData {
+            csrf_token: csrf_token.secret().clone(),
+            nonce: nonce.secret().clone(),
+            pkce_verifier: pkce_verifier.secret().clone(),
+            link_user_id,
+            stay_logged_in: stay_logged_in.unwrap_or(false),
+        },
+        exp: (Utc::now() + PENDING_LOGIN_TIME).timestamp() as usize,
+    };
+    create_jwt_cookie(cookies, "oidc_login", &data, Expiration::Session)?;
+
+    Ok(Redirect::to(auth_url.to_string()))
+}
+
+// the claim can be a boolean, a single value or a list of values like groups or roles
+fn has_admin_claim(claims: &Value, claim: &str, expected: Option<&str>) -> bool {
+    match (claims.get(claim), expected) {
+        (Some(Value::Bool(value)), None) => *value,
+        (Some(Value::String(value)), Some(expected)) => value == expected,
+        (Some(Value::Array(values)), Some(expected)) => {
+            values.iter().any(|value| value.as_str() == Some(expected))
+        }
+        _ => false,
+    }
+}
+
+// the provider allows more in names than logins can have, "jane.doe@example.com" becomes "janedoe"
+fn sanitize_login(value: &str) -> String {
+    value
+        .split('@')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect()
+}
+
+// new accounts take their login from the provider, when it's taken the account has to be linked by hand
+async fn provision_user(tx: &mut sqlx::PgConnection, login: &str, admin: bool) -> ApiResult<User> {
+    insert_user_without_password(tx, login, admin)
+        .await
+        .map_err(|e| {
+            if e.0 == Status::Conflict {
+                ApiResponse::fail(
+                    Status::Conflict,
+                    "an account with this login already exists, log in and link it from your profile",
+                    None,
+                )
+            } else {
+                e
+            }
+        })
+}
+
+fn take_login_data(cookies: &CookieJar<'_>) -> ApiResult<OidcLoginData> {
+    let expired = || {
+        ApiResponse::fail(
+            Status::BadRequest,
+            "single sign-on expired, try again",
+            None,
+        )
+    };
+    let login_data = cookies
+        .get_private("oidc_login")
+        .map(|cookie| cookie.value().to_string())
+        .ok_or_else(expired)?;
+    cookies.remove_private(Cookie::from("oidc_login"));
+
+    let jwt_secret = std::env::var("JWT_SECRET").map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })?;
+    let key = DecodingKey::from_secret(jwt_secret.as_bytes());
+    decode::<JWTData<OidcLoginData>>(&login_data, &key, &Validation::default())
+        .map(|data| data.claims.data)
+        .map_err(|_| expired())
+}
+
+// the provider sends the browser here, so errors go back to the page the login or linking
+// was started from with the message in `error` instead of being shown as JSON
+#[get("/login/oidc/callback?<code>&<state>&<error>")]
+pub async fn oidc_callback(
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    uaip: UserAgentIp,
+    pool: &State<PgPool>,
+    metadata: &State<OidcMetadata>,
+    cookies: &CookieJar<'_>,
+) -> Redirect {
+    let frontend = std::env::var("ALLOWED_ORIGIN").unwrap_or_default();
+    let login_data = take_login_data(cookies);
+    let page = match &login_data {
+        Ok(login_data) if login_data.link_user_id.is_some() => "profile",
+        _ => "login",
+    };
+    let result = async {
+        let login_data = login_data?;
+        let code = authorization_code(&login_data, code, state, error)?;
+        finish_oidc_login(login_data, code, uaip, pool, metadata, cookies).await
+    }
+    .await;
+
+    match result {
+        Ok(()) => Redirect::to(format!("{}/", frontend)),
+        Err((_, response)) => {
+            let message = response
+                .into_inner()
+                .detail
+                .unwrap_or_else(|| "single sign-on failed".to_string());
+            Redirect::to(format!(
+                "{}/{}?error={}",
+                frontend,
+                page,
+                RawStr::new(&message).percent_encode()
+            ))
+        }
+    }
+}
+
+fn authorization_code(
+    login_data: &OidcLoginData,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+) -> ApiResult<String> {
+    if let Some(error) = error {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            format!("identity provider refused the login: {}", error),
+            None,
+        ));
+    }
+    let expired = || {
+        ApiResponse::fail(
+            Status::BadRequest,
+            "single sign-on expired, try again",
+            None,
+        )
+    };
+    let (Some(code), Some(state)) = (code, state) else {
+        return Err(expired());
+    };
+    if state != login_data.csrf_token {
+        return Err(expired());
+    }
+    Ok(code)
+}
+
+// two-factor authentication is left to the identity provider
+async fn finish_oidc_login(
+    login_data: OidcLoginData,
+    code: String,
+    uaip: UserAgentIp,
+    pool: &State<PgPool>,
+    metadata: &State<OidcMetadata>,
+    cookies: &CookieJar<'_>,
+) -> ApiResult<()> {
+    let config = oidc_config()?;
+    let http = http_client()?;
+    let client = oidc_client(&config, &http, metadata).await?;
+
+    let token_response = client
+        .exchange_code(AuthorizationCode::new(code))
+        .map_err(|e| {
+            ApiResponse::fail(
+                Status::BadGateway,
+                "identity provider is not available",
+                Some(&e),
+            )
+        })?
+        .set_pkce_verifier(PkceCodeVerifier::new(login_data.pkce_verifier))
+        .request_async(&http)
+        .await
+        .map_err(|e| {
+            ApiResponse::fail(
+                Status::BadGateway,
+                "identity provider didn't accept the login",
+                Some(&e),
+            )
+        })?;
+    let id_token = token_response.id_token().ok_or_else(|| {
+        ApiResponse::fail(
+            Status::BadGateway,
+            "identity provider didn't return an id token",
+            None,
+        )
+    })?;
+    let claims = id_token
+        .claims(&client.id_token_verifier(), &Nonce::new(login_data.nonce))
+        .map_err(|e| {
+            ApiResponse::fail(
+                Status::BadRequest,
+                "identity provider returned an invalid id token",
+                Some(&e),
+            )
+        })?;
+    let issuer = claims.issuer().to_string();
+    let subject = claims.subject().to_string();
+
+    // the token was verified above, its payload is read again only for claims the library doesn't know
+    let admin = match &config.admin_claim {
+        Some(admin_claim) => {
+            let raw_claims = id_token
+                .to_string()
+                .split('.')
+                .nth(1)
+                .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+                .and_then(|payload| rocket::serde::json::from_slice::<Value>(&payload).ok())
+                .unwrap_or_default();
+            Some(has_admin_claim(
+                &raw_claims,
+                admin_claim,
+                config.admin_value.as_deref(),
+            ))
+        }
+        None => None,
+    };
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let linked_user_id = sqlx::query_scalar!(
+        r#"
+        UPDATE user_identities SET last_login_at = NOW()
+        WHERE issuer = $1 AND subject = $2
+        RETURNING user_id
+        "#,
+        issuer,
+        subject
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let user_id = match (linked_user_id, login_data.link_user_id) {
+        (Some(user_id), Some(link_user_id)) if user_id != link_user_id => {
+            return Err(ApiResponse::fail(
+                Status::Conflict,
+                "this provider account is already linked to a different user",
+                None,
+            ));
+        }
+        (Some(user_id), _) => user_id,
+        (None, link_user_id) => {
+            let user_id = match link_user_id {
+                Some(user_id) => user_id,
+                None if config.auto_provision => {
+                    let login = [
+                        claims.preferred_username().map(|login| login.as_str()),
+                        claims.email().map(|email| email.as_str()),
+                        Some(subject.as_str()),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .map(sanitize_login)
+                    .find(|login| !login.is_empty())
+                    .unwrap_or_default();
+                    provision_user(&mut tx, &login, admin.unwrap_or(false))
+                        .await?
+                        .id
+                }
+                None => {
+                    return Err(ApiResponse::fail(
+                        Status::Forbidden,
+                        "there is no account linked to this provider account",
+                        None,
+                    ));
+                }
+            };
+            sqlx::query!(
+                "INSERT INTO user_identities (user_id, issuer, subject, last_login_at) VALUES ($1, $2, $3, NOW())",
+                user_id,
+                issuer,
+                subject
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+            user_id
+        }
+    };
+
+    // with a mapped claim the provider decides who is an admin on every login,
+    // linking only attaches the identity and leaves the signed in account's rights alone
+    let admin = admin.filter(|_| login_data.link_user_id.is_none());
+    let user = sqlx::query_as!(
+        User,
+        "UPDATE users SET admin = COALESCE($2, admin) WHERE id = $1 RETURNING *",
+        user_id,
+        admin
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    if login_data.link_user_id.is_none() {
+        login_cookie(uaip, pool, cookies, user, login_data.stay_logged_in).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::serde::json::json;
+
+    #[test]
+    fn maps_admin_claims() {
+        let claims = json!({
+            "is_admin": true,
+            "is_staff": false,
+            "role": "admin",
+            "groups": ["users", "admins"],
+            "count": 1,
+        });
+        assert!(has_admin_claim(&claims, "is_admin", None));
+        assert!(!has_admin_claim(&claims, "is_staff", None));
+        assert!(has_admin_claim(&claims, "role", Some("admin")));
+        assert!(!has_admin_claim(&claims, "role", Some("admins")));
+        assert!(has_admin_claim(&claims, "groups", Some("admins")));
+        assert!(!has_admin_claim(&claims, "groups", Some("staff")));
+        assert!(!has_admin_claim(&claims, "missing", None));
+        assert!(!has_admin_claim(&claims, "missing", Some("admin")));
+        assert!(!has_admin_claim(&claims, "count", Some("1")));
+    }
+
+    #[test]
+    fn expected_value_and_claim_type_have_to_match() {
+        let claims = json!({ "is_admin": true, "role": "admin", "groups": [true] });
+        // a boolean claim doesn't count when a value is expected and the other way around
+        assert!(!has_admin_claim(&claims, "is_admin", Some("true")));
+        assert!(!has_admin_claim(&claims, "role", None));
+        assert!(!has_admin_claim(&claims, "groups", None));
+        assert!(!has_admin_claim(&claims, "groups", Some("true")));
+    }
+
+    #[test]
+    fn sanitizes_provider_names_into_logins() {
+        assert_eq!(sanitize_login("jane.doe@example.com"), "janedoe");
+        assert_eq!(sanitize_login("Jane_Doe-2"), "JaneDoe2");
+        assert_eq!(sanitize_login("jane"), "jane");
+        assert_eq!(sanitize_login("@example.com"), "");
+        assert_eq!(sanitize_login("żółw"), "w");
+        assert_eq!(sanitize_login(""), "");
+    }
+}
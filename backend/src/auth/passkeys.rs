@@ -0,0 +1,789 @@
+use crate::auth::endpoints::{login_cookie, UserAgentIp};
+use crate::auth::{AuthUser, JWTData, PendingLoginData};
+use crate::models::{ApiResponse, Passkey, PasskeyChallengeKind, User};
+use crate::{ApiResult, PENDING_LOGIN_TIME};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use coset::cbor::Value;
+use coset::{iana, AsCborValue, CoseKey, KeyType, Label, RegisteredLabelWithPrivate};
+use jsonwebtoken::{decode, DecodingKey, Validation};
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const RP_NAME: &str = "lempek-assets";
+const CHALLENGE_TIMEOUT_MS: u64 = 300_000;
+// COSE algorithm id of ES256, the only one supported
+const ES256: i64 = iana::Algorithm::ES256 as i64;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_DATA: u8 = 0x40;
+
+struct RelyingParty {
+    id: String,
+    origin: String,
+}
+
+// passkeys are bound to the domain of the frontend, by default it's taken from ALLOWED_ORIGIN
+fn relying_party() -> ApiResult<RelyingParty> {
+    let origin = std::env::var("WEBAUTHN_ORIGIN")
+        .or_else(|_| std::env::var("ALLOWED_ORIGIN"))
+        .map_err(|e| {
+            ApiResponse::fail(
+                Status::InternalServerError,
+                "internal server error",
+                Some(&e),
+            )
+        })?;
+    let id = std::env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| {
+        let host = origin
+            .split_once("://")
+            .map_or(origin.as_str(), |(_, host)| host);
+        host.split([':', '/'])
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    });
+    Ok(RelyingParty { id, origin })
+}
+
+// the challenge is kept in the database until the browser sends the signed response back,
+// the private cookie only tells which one was handed to this browser
+struct PasskeyChallengeData {
+    challenge: String,
+    // set when the challenge can only be answered by a passkey of this user
+    user_id: Option<Uuid>,
+}
+
+fn challenge_cookie(kind: PasskeyChallengeKind) -> &'static str {
+    match kind {
+        PasskeyChallengeKind::Registration => "passkey_registration",
+        PasskeyChallengeKind::Login => "passkey_login",
+    }
+}
+
+async fn new_challenge(
+    pool: &PgPool,
+    cookies: &CookieJar<'_>,
+    kind: PasskeyChallengeKind,
+    user_id: Option<Uuid>,
+) -> ApiResult<String> {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let challenge = URL_SAFE_NO_PAD.encode(bytes);
+
+    // we can ignore error because challenges that were never answered can be removed later
+    let _ = sqlx::query!("DELETE FROM passkey_challenges WHERE expires_at <= NOW()")
+        .execute(pool)
+        .await;
+    let id = sqlx::query_scalar!(
+        "INSERT INTO passkey_challenges (kind, challenge, user_id, expires_at) VALUES ($1, $2, $3, $4) RETURNING id",
+        kind as PasskeyChallengeKind,
+        challenge,
+        user_id,
+        Utc::now() + PENDING_LOGIN_TIME
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let cookie = Cookie::build((challenge_cookie(kind), id.to_string()))
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax);
+    cookies.add_private(cookie);
+    Ok(challenge)
+}
+
+// deleting the row is what makes every challenge usable only once, two responses can't both get it
+async fn take_challenge(
+    pool: &PgPool,
+    cookies: &CookieJar<'_>,
+    kind: PasskeyChallengeKind,
+) -> ApiResult<PasskeyChallengeData> {
+    let expired = || {
+        ApiResponse::fail(
+            Status::BadRequest,
+            "passkey challenge expired, try again",
+            None,
+        )
+    };
+    let id = cookies
+        .get_private(challenge_cookie(kind))
+        .and_then(|cookie| cookie.value().parse::<Uuid>().ok())
+        .ok_or_else(expired)?;
+    cookies.remove_private(Cookie::from(challenge_cookie(kind)));
+
+    sqlx::query_as!(
+        PasskeyChallengeData,
+        "DELETE FROM passkey_challenges WHERE id = $1 AND kind = $2 AND expires_at > NOW() RETURNING challenge, user_id",
+        id,
+        kind as PasskeyChallengeKind
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+    .ok_or_else(expired)
+}
+
+fn decode_b64(value: &str) -> ApiResult<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| ApiResponse::fail(Status::BadRequest, "invalid passkey response", None))
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+fn check_client_data(
+    client_data_json: &[u8],
+    kind: &str,
+    challenge: &PasskeyChallengeData,
+    rp: &RelyingParty,
+) -> ApiResult<()> {
+    let client_data: ClientData = rocket::serde::json::from_slice(client_data_json)
+        .map_err(|_| ApiResponse::fail(Status::BadRequest, "invalid passkey response", None))?;
+    if client_data.kind != kind || client_data.challenge != challenge.challenge {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "passkey response doesn't match the challenge",
+            None,
+        ));
+    }
+    if client_data.origin != rp.origin {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "passkey was used on a different site",
+            None,
+        ));
+    }
+    Ok(())
+}
+
+struct AuthenticatorData<'a> {
+    flags: u8,
+    sign_count: u32,
+    // attested credential data, only present when registering
+    rest: &'a [u8],
+}
+
+fn parse_authenticator_data<'a>(
+    data: &'a [u8],
+    rp: &RelyingParty,
+) -> ApiResult<AuthenticatorData<'a>> {
+    if data.len() < 37 {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "invalid passkey response",
+            None,
+        ));
+    }
+    if data[..32] != Sha256::digest(rp.id.as_bytes())[..] {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "passkey belongs to a different site",
+            None,
+        ));
+    }
+    let flags = data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "passkey didn't confirm user presence",
+            None,
+        ));
+    }
+    Ok(AuthenticatorData {
+        flags,
+        sign_count: u32::from_be_bytes([data[33], data[34], data[35], data[36]]),
+        rest: &data[37..],
+    })
+}
+
+#[derive(Serialize)]
+pub struct RelyingPartyEntity {
+    pub id: String,
+    pub name: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Serialize)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub alg: i64,
+}
+
+#[derive(Serialize)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub id: String,
+}
+
+impl CredentialDescriptor {
+    fn new(credential_id: &[u8]) -> Self {
+        Self {
+            kind: "public-key",
+            id: URL_SAFE_NO_PAD.encode(credential_id),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: &'static str,
+    pub user_verification: &'static str,
+}
+
+// in the format of `PublicKeyCredential.parseCreationOptionsFromJSON`
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyCreationOptions {
+    pub challenge: String,
+    pub rp: RelyingPartyEntity,
+    pub user: UserEntity,
+    pub pub_key_cred_params: Vec<CredentialParameters>,
+    pub timeout: u64,
+    pub attestation: &'static str,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+    pub authenticator_selection: AuthenticatorSelection,
+}
+
+// in the format of `PublicKeyCredential.parseRequestOptionsFromJSON`
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyRequestOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    pub timeout: u64,
+    pub allow_credentials: Vec<CredentialDescriptor>,
+    pub user_verification: &'static str,
+}
+
+async fn credential_ids(pool: &PgPool, user_id: Uuid) -> ApiResult<Vec<CredentialDescriptor>> {
+    let ids = sqlx::query_scalar!(
+        "SELECT credential_id FROM passkeys WHERE user_id = $1",
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    Ok(ids.iter().map(|id| CredentialDescriptor::new(id)).collect())
+}
+
+#[post("/user/passkey/options")]
+pub async fn passkey_registration_options(
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>,
+    auth: AuthUser,
+) -> ApiResult<Json<PasskeyCreationOptions>> {
+    let auth = auth?;
+    auth.require_full_access()?;
+    let rp = relying_party()?;
+
+    let exclude_credentials = credential_ids(pool, auth.user_id).await?;
+    let challenge = new_challenge(
+        pool,
+        cookies,
+        PasskeyChallengeKind::Registration,
+        Some(auth.user_id),
+    )
+    .await?;
+
+    Ok(Json(PasskeyCreationOptions {
+        challenge,
+        rp: RelyingPartyEntity {
+            id: rp.id,
+            name: RP_NAME,
+        },
+        user: UserEntity {
+            id: URL_SAFE_NO_PAD.encode(auth.user_id.as_bytes()),
+            name: auth.login,
+            display_name: auth.username,
+        },
+        pub_key_cred_params: vec![CredentialParameters {
+            kind: "public-key",
+            alg: ES256,
+        }],
+        timeout: CHALLENGE_TIMEOUT_MS,
+        attestation: "none",
+        exclude_credentials,
+        authenticator_selection: AuthenticatorSelection {
+            resident_key: "preferred",
+            user_verification: "preferred",
+        },
+    }))
+}
+
+// fields of `AuthenticatorAttestationResponse.toJSON()`, the other ones are decoded from the
+// attestation object instead of trusting the browser
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationCredential {
+    pub raw_id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterPasskeyData {
+    pub name: String,
+    pub credential: RegistrationCredential,
+}
+
+// the attestation object is a CBOR map of the format, the statement and the authenticator data,
+// only "none" attestation is asked for so the statement isn't checked
+fn attested_authenticator_data(attestation_object: &[u8]) -> ApiResult<Vec<u8>> {
+    let invalid = || ApiResponse::fail(Status::BadRequest, "invalid passkey response", None);
+    let value: Value = coset::cbor::de::from_reader(attestation_object).map_err(|_| invalid())?;
+    value
+        .into_map()
+        .map_err(|_| invalid())?
+        .into_iter()
+        .find(|(key, _)| key.as_text() == Some("authData"))
+        .and_then(|(_, value)| value.into_bytes().ok())
+        .ok_or_else(invalid)
+}
+
+// the uncompressed point of an ES256 COSE key
+fn es256_public_key(key: &CoseKey) -> ApiResult<Vec<u8>> {
+    let unsupported = || {
+        ApiResponse::fail(
+            Status::BadRequest,
+            "only ES256 passkeys are supported",
+            None,
+        )
+    };
+    if key.kty != KeyType::Assigned(iana::KeyType::EC2)
+        || key.alg != Some(RegisteredLabelWithPrivate::Assigned(iana::Algorithm::ES256))
+    {
+        return Err(unsupported());
+    }
+    let param = |label: iana::Ec2KeyParameter| {
+        key.params
+            .iter()
+            .find(|(l, _)| *l == Label::Int(label as i64))
+            .map(|(_, value)| value)
+    };
+    let curve = param(iana::Ec2KeyParameter::Crv)
+        .and_then(Value::as_integer)
+        .map(i128::from);
+    let x = param(iana::Ec2KeyParameter::X).and_then(Value::as_bytes);
+    let y = param(iana::Ec2KeyParameter::Y).and_then(Value::as_bytes);
+    match (curve, x, y) {
+        (Some(curve), Some(x), Some(y))
+            if curve == iana::EllipticCurve::P_256 as i128 && x.len() == 32 && y.len() == 32 =>
+        {
+            Ok([&[0x04][..], x, y].concat())
+        }
+        _ => Err(unsupported()),
+    }
+}
+
+// returns the credential id and the public key as an uncompressed point
+fn verify_registration(
+    credential: &RegistrationCredential,
+    challenge: &PasskeyChallengeData,
+    rp: &RelyingParty,
+) -> ApiResult<(Vec<u8>, Vec<u8>)> {
+    let response = &credential.response;
+    check_client_data(
+        &decode_b64(&response.client_data_json)?,
+        "webauthn.create",
+        challenge,
+        rp,
+    )?;
+
+    let authenticator_data =
+        attested_authenticator_data(&decode_b64(&response.attestation_object)?)?;
+    let authenticator_data = parse_authenticator_data(&authenticator_data, rp)?;
+    let invalid = || ApiResponse::fail(Status::BadRequest, "invalid passkey response", None);
+    if authenticator_data.flags & FLAG_ATTESTED_DATA == 0 {
+        return Err(invalid());
+    }
+
+    // aaguid (16 bytes), credential id length (2 bytes), credential id, COSE public key
+    // and the extensions if the authenticator sent any
+    let attested = authenticator_data.rest;
+    if attested.len() < 18 {
+        return Err(invalid());
+    }
+    let id_len = u16::from_be_bytes([attested[16], attested[17]]) as usize;
+    let credential_id = attested.get(18..18 + id_len).ok_or_else(invalid)?;
+    if decode_b64(&credential.raw_id)? != credential_id {
+        return Err(invalid());
+    }
+
+    let cose_key: Value =
+        coset::cbor::de::from_reader(&attested[18 + id_len..]).map_err(|_| invalid())?;
+    let cose_key = CoseKey::from_cbor_value(cose_key).map_err(|_| invalid())?;
+    let point = es256_public_key(&cose_key)?;
+    VerifyingKey::from_sec1_bytes(&point).map_err(|_| invalid())?;
+
+    Ok((credential_id.to_vec(), point))
+}
+
+fn map_passkey_error(e: sqlx::Error) -> (Status, Json<ApiResponse>) {
+    if let sqlx::Error::Database(db_err) = &e
+        && db_err.is_unique_violation()
+    {
+        if db_err.constraint() == Some("passkeys_credential_id_key") {
+            ApiResponse::fail(Status::Conflict, "this passkey is already registered", None)
+        } else {
+            ApiResponse::fail(
+                Status::Conflict,
+                "passkey with this name already exists",
+                None,
+            )
+        }
+    } else {
+        ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+    }
+}
+
+fn validate_passkey_name(name: &str) -> ApiResult<&str> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "passkey name must be provided",
+            None,
+        ));
+    }
+    Ok(name)
+}
+
+#[post("/user/passkey", format = "json", data = "<data>")]
+pub async fn register_passkey(
+    data: Json<RegisterPasskeyData>,
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>,
+    auth: AuthUser,
+) -> ApiResult<Json<Passkey>> {
+    let auth = auth?;
+    auth.require_full_access()?;
+    let name = validate_passkey_name(&data.name)?;
+    let rp = relying_party()?;
+
+    let challenge = take_challenge(pool, cookies, PasskeyChallengeKind::Registration).await?;
+    if challenge.user_id != Some(auth.user_id) {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "passkey challenge expired, try again",
+            None,
+        ));
+    }
+    let (credential_id, public_key) = verify_registration(&data.credential, &challenge, &rp)?;
+
+    let passkey = sqlx::query_as!(
+        Passkey,
+        r#"
+        INSERT INTO passkeys (user_id, name, credential_id, public_key)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, user_id, name, last_used_at, created_at
+        "#,
+        auth.user_id,
+        name,
+        credential_id,
+        public_key
+    )
+    .fetch_one(pool.inner())
+    .await
+    .map_err(map_passkey_error)?;
+
+    Ok(Json(passkey))
+}
+
+#[get("/user/passkeys")]
+pub async fn get_passkeys(pool: &State<PgPool>, auth: AuthUser) -> ApiResult<Json<Vec<Passkey>>> {
+    let auth = auth?;
+    let passkeys = sqlx::query_as!(
+        Passkey,
+        r#"
+        SELECT id, user_id, name, last_used_at, created_at
+        FROM passkeys
+        WHERE user_id = $1
+        ORDER BY created_at
+        "#,
+        auth.user_id
+    )
+    .fetch_all(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(passkeys))
+}
+
+#[derive(Deserialize)]
+pub struct RenamePasskeyData {
+    pub id: Uuid,
+    pub name: String,
+}
+
+#[patch("/user/passkey", format = "json", data = "<data>")]
+pub async fn rename_passkey(
+    data: Json<RenamePasskeyData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<Passkey>> {
+    let auth = auth?;
+    auth.require_full_access()?;
+    let name = validate_passkey_name(&data.name)?;
+
+    let passkey = sqlx::query_as!(
+        Passkey,
+        r#"
+        UPDATE passkeys SET name = $3
+        WHERE id = $1 AND user_id = $2
+        RETURNING id, user_id, name, last_used_at, created_at
+        "#,
+        data.id,
+        auth.user_id,
+        name
+    )
+    .fetch_optional(pool.inner())
+    .await
+    .map_err(map_passkey_error)?
+    .ok_or_else(|| ApiResponse::fail(Status::NotFound, "passkey not found", None))?;
+
+    Ok(Json(passkey))
+}
+
+#[derive(Deserialize)]
+pub struct RevokePasskeyData {
+    pub id: Uuid,
+}
+
+#[delete("/user/passkey", format = "json", data = "<data>")]
+pub async fn revoke_passkey(
+    data: Json<RevokePasskeyData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult {
+    let auth = auth?;
+    auth.require_full_access()?;
+
+    let result = sqlx::query!(
+        "DELETE FROM passkeys WHERE id = $1 AND user_id = $2",
+        data.id,
+        auth.user_id
+    )
+    .execute(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiResponse::fail(
+            Status::NotFound,
+            "passkey not found",
+            None,
+        ));
+    }
+
+    Ok((
+        Status::NoContent,
+        ApiResponse::success_with("revoked passkey"),
+    ))
+}
+
+// after the password was accepted and a second factor is needed only passkeys of that user are allowed,
+// otherwise any discoverable passkey can log in without a password
+#[post("/login/passkey/options")]
+pub async fn passkey_login_options(
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>,
+) -> ApiResult<Json<PasskeyRequestOptions>> {
+    let rp = relying_party()?;
+
+    let pending_user = match cookies.get_private("pending_login") {
+        Some(pending) => {
+            let jwt_secret = std::env::var("JWT_SECRET").map_err(|e| {
+                ApiResponse::fail(
+                    Status::InternalServerError,
+                    "internal server error",
+                    Some(&e),
+                )
+            })?;
+            let key = DecodingKey::from_secret(jwt_secret.as_bytes());
+            decode::<JWTData<PendingLoginData>>(pending.value(), &key, &Validation::default())
+                .ok()
+                .map(|data| data.claims.data.user_id)
+        }
+        None => None,
+    };
+
+    let allow_credentials = match pending_user {
+        Some(user_id) => credential_ids(pool, user_id).await?,
+        None => Vec::new(),
+    };
+    let challenge = new_challenge(pool, cookies, PasskeyChallengeKind::Login, pending_user).await?;
+
+    Ok(Json(PasskeyRequestOptions {
+        challenge,
+        rp_id: rp.id,
+        timeout: CHALLENGE_TIMEOUT_MS,
+        allow_credentials,
+        user_verification: if pending_user.is_some() {
+            "preferred"
+        } else {
+            "required"
+        },
+    }))
+}
+
+// fields of `AuthenticatorAssertionResponse.toJSON()`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginPasskeyData {
+    pub raw_id: String,
+    pub response: AssertionResponse,
+    #[serde(default, rename = "stay_logged_in")]
+    pub stay_logged_in: bool,
+}
+
+#[post("/login/passkey", format = "json", data = "<data>")]
+pub async fn login_passkey(
+    data: Json<LoginPasskeyData>,
+    uaip: UserAgentIp,
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>,
+    user: AuthUser,
+) -> ApiResult {
+    if user.is_ok() {
+        return Err(ApiResponse::fail(
+            Status::Conflict,
+            "you are already logged in",
+            None,
+        ));
+    }
+    let rp = relying_party()?;
+    let challenge = take_challenge(pool, cookies, PasskeyChallengeKind::Login).await?;
+
+    let client_data_json = decode_b64(&data.response.client_data_json)?;
+    check_client_data(&client_data_json, "webauthn.get", &challenge, &rp)?;
+    let raw_authenticator_data = decode_b64(&data.response.authenticator_data)?;
+    let authenticator_data = parse_authenticator_data(&raw_authenticator_data, &rp)?;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let passkey = sqlx::query!(
+        "SELECT id, user_id, public_key, sign_count FROM passkeys WHERE credential_id = $1 FOR UPDATE",
+        decode_b64(&data.raw_id)?
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+    .ok_or_else(|| ApiResponse::fail(Status::BadRequest, "unknown passkey", None))?;
+
+    let mut message = raw_authenticator_data.clone();
+    message.extend_from_slice(&Sha256::digest(&client_data_json));
+    let key = VerifyingKey::from_sec1_bytes(&passkey.public_key).map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })?;
+    let invalid_signature =
+        || ApiResponse::fail(Status::BadRequest, "invalid passkey signature", None);
+    let signature = Signature::from_der(&decode_b64(&data.response.signature)?)
+        .map_err(|_| invalid_signature())?;
+    key.verify(&message, &signature)
+        .map_err(|_| invalid_signature())?;
+
+    match challenge.user_id {
+        // second factor after the password
+        Some(user_id) if user_id != passkey.user_id => {
+            return Err(ApiResponse::fail(
+                Status::BadRequest,
+                "passkey belongs to a different account",
+                None,
+            ));
+        }
+        Some(_) => {}
+        // without a password the authenticator has to verify the user itself (pin, biometrics)
+        None if authenticator_data.flags & FLAG_USER_VERIFIED == 0 => {
+            return Err(ApiResponse::fail(
+                Status::BadRequest,
+                "passkey didn't verify the user, log in with your password first",
+                None,
+            ));
+        }
+        None => {}
+    }
+
+    // authenticators that don't count signatures always send 0
+    let sign_count = authenticator_data.sign_count as i64;
+    if (sign_count != 0 || passkey.sign_count != 0) && sign_count <= passkey.sign_count {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "passkey signature counter went backwards, it may have been cloned",
+            None,
+        ));
+    }
+
+    sqlx::query!(
+        "UPDATE passkeys SET sign_count = $2, last_used_at = NOW() WHERE id = $1",
+        passkey.id,
+        sign_count
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", passkey.user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    cookies.remove_private(Cookie::from("pending_login"));
+    login_cookie(uaip, pool, cookies, user, data.stay_logged_in).await?;
+    Ok((Status::Ok, ApiResponse::success()))
+}
@@ -0,0 +1,127 @@
+use crate::auth::lockout::clear_login_failures;
+use crate::auth::tokens::{generate_token, hash_token};
+use crate::auth::users::replace_password;
+use crate::auth::AuthAdminUser;
+use crate::models::ApiResponse;
+use crate::{ApiResult, PASSWORD_RESET_TIME};
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const RESET_PREFIX: &str = "pwr_";
+
+#[derive(Deserialize)]
+pub struct NewPasswordResetData {
+    pub id: Uuid,
+}
+
+#[derive(Serialize)]
+pub struct CreatedPasswordReset {
+    // only returned once, the link is `/reset-password?token=...`
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+// replaces any link the user got before, the current password keeps working until the link is used
+#[post("/user/password/reset-token", format = "json", data = "<data>")]
+pub async fn create_password_reset(
+    data: Json<NewPasswordResetData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult<Json<CreatedPasswordReset>> {
+    let admin = admin?;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    sqlx::query!(
+        "DELETE FROM password_resets WHERE user_id = $1 OR expires_at <= NOW()",
+        data.id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let token = generate_token(RESET_PREFIX);
+    let expires_at = sqlx::query_scalar!(
+        r#"
+        INSERT INTO password_resets (user_id, token_hash, created_by, expires_at) VALUES ($1, $2, $3, $4)
+        RETURNING expires_at
+        "#,
+        data.id,
+        hash_token(&token),
+        admin.user_id,
+        Utc::now() + PASSWORD_RESET_TIME
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+        if let sqlx::Error::Database(db_err) = &e
+            && db_err.is_foreign_key_violation()
+        {
+            return ApiResponse::fail(Status::NotFound, "user not found", None);
+        }
+        ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+    })?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    log::info!(
+        "admin {} created a password reset link for user {}",
+        admin.user_id,
+        data.id
+    );
+    Ok(Json(CreatedPasswordReset { token, expires_at }))
+}
+
+#[derive(Deserialize)]
+pub struct UsePasswordResetData {
+    pub token: String,
+    pub new_password: String,
+}
+
+// works without logging in, the user is logged out everywhere and logs in with the new password
+#[post("/password/reset", format = "json", data = "<data>")]
+pub async fn use_password_reset(
+    data: Json<UsePasswordResetData>,
+    pool: &State<PgPool>,
+) -> ApiResult {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    // deleting it is what makes the link single-use, two requests can't both get the row
+    let user_id = sqlx::query_scalar!(
+        "DELETE FROM password_resets WHERE token_hash = $1 AND expires_at > NOW() RETURNING user_id",
+        hash_token(data.token.trim())
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+    .ok_or_else(|| {
+        ApiResponse::fail(
+            Status::Gone,
+            "password reset link is invalid or expired",
+            None,
+        )
+    })?;
+
+    let login = replace_password(&mut tx, user_id, &data.new_password).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    clear_login_failures(pool.inner(), &login).await?;
+    log::info!("user {} set a new password with a reset link", user_id);
+    Ok((Status::Ok, ApiResponse::success()))
+}
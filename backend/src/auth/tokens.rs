@@ -0,0 +1,217 @@
+use crate::auth::{ensure_active, AuthUser, UserData};
+use crate::models::{AccessToken, ApiResponse, TokenScope, User};
+use crate::ApiResult;
+use chrono::{DateTime, Utc};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const TOKEN_PREFIX: &str = "lat_";
+
+// attached to the user when the request was authenticated with an access token instead of cookies
+#[derive(Debug, Clone)]
+pub struct TokenAccess {
+    pub scope: TokenScope,
+    pub folder_id: Option<Uuid>,
+}
+
+pub(crate) fn generate_token(prefix: &str) -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let random = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("{}{}", prefix, random)
+}
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub async fn user_from_access_token(pool: &PgPool, token: &str) -> ApiResult<UserData> {
+    let access = sqlx::query!(
+        r#"
+        UPDATE access_tokens SET last_used_at = NOW()
+        WHERE token_hash = $1 AND (expires_at IS NULL OR expires_at > NOW())
+        RETURNING user_id, scope AS "scope: TokenScope", folder_id
+        "#,
+        hash_token(token)
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+    .ok_or_else(|| ApiResponse::fail(Status::Unauthorized, "invalid access token", None))?;
+
+    let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", access.user_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    ensure_active(&user)?;
+
+    let mut user_data: UserData = user.into();
+    user_data.token = Some(TokenAccess {
+        scope: access.scope,
+        folder_id: access.folder_id,
+    });
+    Ok(user_data)
+}
+
+#[get("/user/access-tokens")]
+pub async fn get_access_tokens(
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<Vec<AccessToken>>> {
+    let auth = auth?;
+    auth.require_full_access()?;
+    let tokens = sqlx::query_as!(
+        AccessToken,
+        r#"
+        SELECT id, user_id, name, scope AS "scope: TokenScope", folder_id, expires_at, last_used_at, created_at
+        FROM access_tokens
+        WHERE user_id = $1
+        ORDER BY created_at DESC
+        "#,
+        auth.user_id
+    )
+    .fetch_all(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(tokens))
+}
+
+#[derive(Deserialize)]
+pub struct NewAccessTokenData {
+    pub name: String,
+    pub scope: TokenScope,
+    pub folder_id: Option<Uuid>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedAccessToken {
+    // the only time the token is shown, only its hash is kept
+    pub token: String,
+    #[serde(flatten)]
+    pub access_token: AccessToken,
+}
+
+#[post("/user/access-token", format = "json", data = "<data>")]
+pub async fn create_access_token(
+    data: Json<NewAccessTokenData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<CreatedAccessToken>> {
+    let auth = auth?;
+    auth.require_full_access()?;
+
+    let name = data.name.trim();
+    if name.is_empty() {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "token name must be provided",
+            None,
+        ));
+    }
+    if data.scope == TokenScope::Admin && data.folder_id.is_some() {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "admin tokens can't be limited to a folder",
+            None,
+        ));
+    }
+    if data
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "expiration date must be in the future",
+            None,
+        ));
+    }
+
+    let token = generate_token(TOKEN_PREFIX);
+    let access_token = sqlx::query_as!(
+        AccessToken,
+        r#"
+        INSERT INTO access_tokens (user_id, name, token_hash, scope, folder_id, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, user_id, name, scope AS "scope: TokenScope", folder_id, expires_at, last_used_at, created_at
+        "#,
+        auth.user_id,
+        name,
+        hash_token(&token),
+        data.scope as TokenScope,
+        data.folder_id,
+        data.expires_at,
+    )
+    .fetch_one(pool.inner())
+    .await
+    .map_err(|e| {
+        if let sqlx::Error::Database(db_err) = &e {
+            if db_err.is_unique_violation() {
+                return ApiResponse::fail(
+                    Status::Conflict,
+                    "token with this name already exists",
+                    None,
+                );
+            }
+            if db_err.is_foreign_key_violation() {
+                return ApiResponse::fail(Status::NotFound, "folder not found", None);
+            }
+        }
+        ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+    })?;
+
+    Ok(Json(CreatedAccessToken {
+        token,
+        access_token,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RevokeAccessTokenData {
+    pub id: Uuid,
+}
+
+// admins can revoke tokens of any user
+#[delete("/user/access-token", format = "json", data = "<data>")]
+pub async fn revoke_access_token(
+    data: Json<RevokeAccessTokenData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult {
+    let auth = auth?;
+    auth.require_full_access()?;
+
+    let result = sqlx::query!(
+        "DELETE FROM access_tokens WHERE id = $1 AND (user_id = $2 OR $3)",
+        data.id,
+        auth.user_id,
+        auth.admin
+    )
+    .execute(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiResponse::fail(
+            Status::NotFound,
+            "access token not found",
+            None,
+        ));
+    }
+
+    Ok((
+        Status::NoContent,
+        ApiResponse::success_with("revoked access token"),
+    ))
+}
@@ -0,0 +1,498 @@
+use crate::auth::tokens::hash_token;
+use crate::auth::{verify_password, AuthUser};
+use crate::models::{ApiResponse, User};
+use crate::ApiResult;
+use chrono::Utc;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+const TOTP_ISSUER: &str = "lempek-assets";
+const TOTP_STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+fn build_totp(secret: &str, login: &str) -> ApiResult<TOTP> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| {
+            ApiResponse::fail(
+                Status::InternalServerError,
+                "internal server error",
+                Some(&e),
+            )
+        })?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        TOTP_STEP,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        login.to_string(),
+    )
+    .map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })
+}
+
+// finds the time step the code was made for, one step of clock drift is allowed both ways
+// and steps up to the last used one are skipped so every code works only once
+fn find_totp_step(totp: &TOTP, code: &str, last_step: Option<i64>) -> Option<i64> {
+    let current = Utc::now().timestamp() as u64 / TOTP_STEP;
+    (current - 1..=current + 1)
+        .filter(|step| last_step.is_none_or(|last| *step as i64 > last))
+        .find(|step| totp.check(code, step * TOTP_STEP))
+        .map(|step| step as i64)
+}
+
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    OsRng.fill_bytes(&mut bytes);
+    let code = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+// old codes stop working as soon as new ones are made
+async fn replace_recovery_codes(tx: &mut PgConnection, user_id: Uuid) -> ApiResult<Vec<String>> {
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let codes = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect::<Vec<_>>();
+    let hashes = codes.iter().map(|c| hash_token(c)).collect::<Vec<_>>();
+
+    sqlx::query!(
+        "INSERT INTO recovery_codes (user_id, code_hash) SELECT $1, UNNEST($2::text[])",
+        user_id,
+        &hashes
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(codes)
+}
+
+// accepts a code from the authenticator app or one of the unused recovery codes
+pub async fn verify_second_factor(tx: &mut PgConnection, user: &User, code: &str) -> ApiResult<()> {
+    let code = code.trim();
+    let Some(secret) = user.totp_secret.as_deref().filter(|_| user.totp_enabled) else {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "two-factor authentication is not enabled",
+            None,
+        ));
+    };
+
+    let totp = build_totp(secret, &user.login)?;
+    if let Some(step) = find_totp_step(&totp, code, user.totp_last_step) {
+        // the condition stops two requests from using the same code at once
+        let result = sqlx::query!(
+            "UPDATE users SET totp_last_step = $2 WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)",
+            user.id,
+            step
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+        if result.rows_affected() == 1 {
+            return Ok(());
+        }
+    }
+
+    let result = sqlx::query!(
+        "UPDATE recovery_codes SET used_at = NOW() WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+        user.id,
+        hash_token(&code.to_lowercase())
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    if result.rows_affected() == 1 {
+        Ok(())
+    } else {
+        Err(ApiResponse::fail(
+            Status::BadRequest,
+            "invalid two-factor code",
+            None,
+        ))
+    }
+}
+
+// admins without 2FA can't use admin endpoints or admin rights on folders while the setting is on,
+// they can still enrol
+pub async fn is_missing_required_2fa(pool: &PgPool, user_id: Uuid) -> ApiResult<bool> {
+    Ok(sqlx::query_scalar!(
+        "SELECT (SELECT require_admin_2fa FROM settings) AND NOT totp_enabled FROM users WHERE id = $1",
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+    .unwrap_or(false))
+}
+
+// accounts from single sign-on or the directory don't have a local password to confirm,
+// for them the session they logged in with is enough
+fn check_password(user: &User, password: &str) -> ApiResult<()> {
+    if user.password.is_none() {
+        return Ok(());
+    }
+    let valid = verify_password(user.password.as_deref(), password)?;
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiResponse::fail(
+            Status::BadRequest,
+            "wrong password",
+            None,
+        ))
+    }
+}
+
+async fn get_user(tx: &mut PgConnection, user_id: Uuid) -> ApiResult<User> {
+    sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", user_id)
+        .fetch_one(tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))
+}
+
+#[derive(Serialize)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: i64,
+}
+
+#[get("/user/2fa")]
+pub async fn get_two_factor(
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<TwoFactorStatus>> {
+    let auth = auth?;
+    let status = sqlx::query_as!(
+        TwoFactorStatus,
+        r#"
+        SELECT u.totp_enabled AS enabled,
+               (SELECT COUNT(*) FROM recovery_codes r WHERE r.user_id = u.id AND r.used_at IS NULL) AS "recovery_codes_left!"
+        FROM users u
+        WHERE u.id = $1
+        "#,
+        auth.user_id
+    )
+    .fetch_one(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(status))
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorSetupData {
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct TwoFactorSetup {
+    pub secret: String,
+    // otpauth:// URI, the frontend shows it as a QR code
+    pub uri: String,
+}
+
+// starts enrolment, 2FA is enabled only after the first code is confirmed
+#[post("/user/2fa/setup", format = "json", data = "<data>")]
+pub async fn setup_two_factor(
+    data: Json<TwoFactorSetupData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<TwoFactorSetup>> {
+    let auth = auth?;
+    auth.require_full_access()?;
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let user = get_user(&mut tx, auth.user_id).await?;
+    check_password(&user, &data.password)?;
+    if user.totp_enabled {
+        return Err(ApiResponse::fail(
+            Status::Conflict,
+            "two-factor authentication is already enabled",
+            None,
+        ));
+    }
+
+    let secret = Secret::generate_secret().to_encoded().to_string();
+    let totp = build_totp(&secret, &user.login)?;
+
+    sqlx::query!(
+        "UPDATE users SET totp_secret = $2, totp_last_step = NULL WHERE id = $1",
+        user.id,
+        secret
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(TwoFactorSetup {
+        secret,
+        uri: totp.get_url(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorCodeData {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    // shown only once, only hashes are kept
+    pub recovery_codes: Vec<String>,
+}
+
+#[post("/user/2fa/enable", format = "json", data = "<data>")]
+pub async fn enable_two_factor(
+    data: Json<TwoFactorCodeData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<RecoveryCodes>> {
+    let auth = auth?;
+    auth.require_full_access()?;
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let user = get_user(&mut tx, auth.user_id).await?;
+    if user.totp_enabled {
+        return Err(ApiResponse::fail(
+            Status::Conflict,
+            "two-factor authentication is already enabled",
+            None,
+        ));
+    }
+    let Some(secret) = &user.totp_secret else {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "two-factor setup was not started",
+            None,
+        ));
+    };
+
+    let totp = build_totp(secret, &user.login)?;
+    let Some(step) = find_totp_step(&totp, data.code.trim(), None) else {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "invalid two-factor code",
+            None,
+        ));
+    };
+
+    sqlx::query!(
+        "UPDATE users SET totp_enabled = TRUE, totp_last_step = $2 WHERE id = $1",
+        user.id,
+        step
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let recovery_codes = replace_recovery_codes(&mut tx, user.id).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+#[post("/user/2fa/recovery-codes", format = "json", data = "<data>")]
+pub async fn regenerate_recovery_codes(
+    data: Json<TwoFactorCodeData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<RecoveryCodes>> {
+    let auth = auth?;
+    auth.require_full_access()?;
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let user = get_user(&mut tx, auth.user_id).await?;
+    verify_second_factor(&mut tx, &user, &data.code).await?;
+    let recovery_codes = replace_recovery_codes(&mut tx, user.id).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+#[derive(Deserialize)]
+pub struct DisableTwoFactorData {
+    pub password: String,
+    pub code: String,
+}
+
+#[delete("/user/2fa", format = "json", data = "<data>")]
+pub async fn disable_two_factor(
+    data: Json<DisableTwoFactorData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult {
+    let auth = auth?;
+    auth.require_full_access()?;
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let user = get_user(&mut tx, auth.user_id).await?;
+    check_password(&user, &data.password)?;
+    verify_second_factor(&mut tx, &user, &data.code).await?;
+
+    let required = sqlx::query_scalar!("SELECT require_admin_2fa FROM settings")
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    if user.admin && required {
+        return Err(ApiResponse::fail(
+            Status::Forbidden,
+            "two-factor authentication is required for admin accounts",
+            None,
+        ));
+    }
+
+    sqlx::query!(
+        "UPDATE users SET totp_secret = NULL, totp_enabled = FALSE, totp_last_step = NULL WHERE id = $1",
+        user.id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok((
+        Status::NoContent,
+        ApiResponse::success_with("disabled two-factor authentication"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::endpoints::insert_user;
+
+    fn current_step() -> u64 {
+        Utc::now().timestamp() as u64 / TOTP_STEP
+    }
+
+    fn new_totp() -> (String, TOTP) {
+        let secret = Secret::generate_secret().to_encoded().to_string();
+        let totp = build_totp(&secret, "alice").unwrap();
+        (secret, totp)
+    }
+
+    #[test]
+    fn accepts_one_step_of_drift() {
+        let (_, totp) = new_totp();
+        let step = current_step();
+        let code = totp.generate(step * TOTP_STEP);
+        assert_eq!(find_totp_step(&totp, &code, None), Some(step as i64));
+        let code = totp.generate((step + 1) * TOTP_STEP);
+        assert_eq!(find_totp_step(&totp, &code, None), Some(step as i64 + 1));
+        let code = totp.generate((step - 3) * TOTP_STEP);
+        assert_eq!(find_totp_step(&totp, &code, None), None);
+    }
+
+    #[test]
+    fn skips_steps_up_to_the_last_used_one() {
+        let (_, totp) = new_totp();
+        let step = current_step() + 1;
+        let code = totp.generate(step * TOTP_STEP);
+        assert_eq!(find_totp_step(&totp, &code, Some(step as i64)), None);
+        assert_eq!(find_totp_step(&totp, &code, Some(step as i64 + 1)), None);
+        assert_eq!(
+            find_totp_step(&totp, &code, Some(step as i64 - 1)),
+            Some(step as i64)
+        );
+    }
+
+    async fn user_with_two_factor(pool: &PgPool) -> (User, TOTP) {
+        let (secret, totp) = new_totp();
+        let mut conn = pool.acquire().await.unwrap();
+        let user = insert_user(&mut conn, "alice", "password1", false)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "UPDATE users SET totp_secret = $2, totp_enabled = TRUE WHERE id = $1",
+            user.id,
+            secret
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        (get_user(&mut conn, user.id).await.unwrap(), totp)
+    }
+
+    #[sqlx::test]
+    async fn code_works_only_once(pool: PgPool) {
+        let (user, totp) = user_with_two_factor(&pool).await;
+        let code = totp.generate((current_step() + 1) * TOTP_STEP);
+        let mut conn = pool.acquire().await.unwrap();
+
+        assert!(verify_second_factor(&mut conn, &user, &code).await.is_ok());
+        let user_after = get_user(&mut conn, user.id).await.unwrap();
+        assert!(verify_second_factor(&mut conn, &user_after, &code)
+            .await
+            .is_err());
+        // a request that loaded the user before the code was used is stopped by the database
+        assert!(verify_second_factor(&mut conn, &user, &code).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn recovery_code_works_only_once(pool: PgPool) {
+        let (user, _) = user_with_two_factor(&pool).await;
+        let mut conn = pool.acquire().await.unwrap();
+        let codes = replace_recovery_codes(&mut conn, user.id).await.unwrap();
+
+        let code = codes[0].to_uppercase();
+        assert!(verify_second_factor(&mut conn, &user, &code).await.is_ok());
+        assert!(verify_second_factor(&mut conn, &user, &code).await.is_err());
+        assert!(verify_second_factor(&mut conn, &user, &codes[1])
+            .await
+            .is_ok());
+    }
+}
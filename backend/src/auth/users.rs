@@ -0,0 +1,365 @@
+use crate::auth::lockout::clear_login_failures;
+use crate::auth::{AdminData, AuthAdminUser};
+use crate::models::ApiResponse;
+use crate::ApiResult;
+use bcrypt::{hash, DEFAULT_COST};
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection, PgPool};
+use uuid::Uuid;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Serialize, FromRow)]
+pub struct AdminUserListItem {
+    pub id: Uuid,
+    pub login: String,
+    pub username: String,
+    pub admin: bool,
+    pub deactivated: bool,
+    pub totp_enabled: bool,
+    // accounts from single sign-on or LDAP don't have one
+    pub has_password: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct UserPage {
+    pub users: Vec<AdminUserListItem>,
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+}
+
+// `page` starts at 1, `search` matches a part of the login or the username
+#[get("/users?<page>&<per_page>&<search>")]
+pub async fn get_users(
+    page: Option<i64>,
+    per_page: Option<i64>,
+    search: Option<&str>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult<Json<UserPage>> {
+    let _admin = admin?;
+    let page = page.unwrap_or(1).max(1);
+    let per_page = per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let search = search.map(str::trim).filter(|s| !s.is_empty());
+
+    let total = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!" FROM users
+        WHERE $1::text IS NULL OR login ILIKE '%' || $1 || '%' OR username ILIKE '%' || $1 || '%'
+        "#,
+        search
+    )
+    .fetch_one(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let users = sqlx::query_as!(
+        AdminUserListItem,
+        r#"
+        SELECT id, login, username, admin, deactivated, totp_enabled,
+               password IS NOT NULL AS "has_password!", created_at, updated_at
+        FROM users
+        WHERE $1::text IS NULL OR login ILIKE '%' || $1 || '%' OR username ILIKE '%' || $1 || '%'
+        ORDER BY login
+        LIMIT $2 OFFSET $3
+        "#,
+        search,
+        per_page,
+        (page - 1) * per_page
+    )
+    .fetch_all(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(UserPage {
+        users,
+        total,
+        page,
+        per_page,
+    }))
+}
+
+// admins can't lock themselves out, so there is always at least one active admin left
+fn not_self(admin: &AdminData, id: Uuid, action: &str) -> ApiResult<()> {
+    if admin.user_id == id {
+        Err(ApiResponse::fail(
+            Status::BadRequest,
+            format!("you can't {} your own account", action),
+            None,
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn user_not_found() -> (Status, Json<ApiResponse>) {
+    ApiResponse::fail(Status::NotFound, "user not found", None)
+}
+
+// returns how many sessions were ended
+pub async fn remove_sessions(tx: &mut PgConnection, id: Uuid) -> ApiResult<u64> {
+    let result = sqlx::query!("DELETE FROM user_tokens WHERE user_id = $1", id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    Ok(result.rows_affected())
+}
+
+// sets a password someone else chose or reset and logs the user out everywhere, returns the login,
+// the lockout of the account should be lifted with `clear_login_failures` after the commit
+pub async fn replace_password(
+    tx: &mut PgConnection,
+    id: Uuid,
+    password: &str,
+) -> ApiResult<String> {
+    if password.len() < 8 {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "password must have at least 8 characters",
+            None,
+        ));
+    }
+
+    let hashed = hash(password, DEFAULT_COST).map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })?;
+
+    let login = sqlx::query_scalar!(
+        "UPDATE users SET password = $2 WHERE id = $1 RETURNING login",
+        id,
+        hashed
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+    .ok_or_else(user_not_found)?;
+    remove_sessions(tx, id).await?;
+    Ok(login)
+}
+
+#[derive(Deserialize)]
+pub struct SetAdminData {
+    pub id: Uuid,
+    pub admin: bool,
+}
+
+#[patch("/user/admin", format = "json", data = "<data>")]
+pub async fn set_admin(
+    data: Json<SetAdminData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult {
+    let admin = admin?;
+    not_self(&admin, data.id, "change admin rights of")?;
+
+    let result = sqlx::query!(
+        "UPDATE users SET admin = $2 WHERE id = $1",
+        data.id,
+        data.admin
+    )
+    .execute(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    if result.rows_affected() == 0 {
+        return Err(user_not_found());
+    }
+
+    log::info!(
+        "admin {} set admin rights of user {} to {}",
+        admin.user_id,
+        data.id,
+        data.admin
+    );
+    Ok((Status::Ok, ApiResponse::success()))
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordData {
+    pub id: Uuid,
+    pub new_password: String,
+}
+
+// the user is logged out everywhere and has to use the new password
+#[patch("/user/password/reset", format = "json", data = "<data>")]
+pub async fn reset_password(
+    data: Json<ResetPasswordData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult {
+    let admin = admin?;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let login = replace_password(&mut tx, data.id, &data.new_password).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    clear_login_failures(pool.inner(), &login).await?;
+    log::info!(
+        "admin {} reset the password of user {}",
+        admin.user_id,
+        data.id
+    );
+    Ok((Status::Ok, ApiResponse::success()))
+}
+
+#[derive(Deserialize)]
+pub struct SetDeactivatedData {
+    pub id: Uuid,
+    pub deactivated: bool,
+}
+
+#[patch("/user/deactivated", format = "json", data = "<data>")]
+pub async fn set_deactivated(
+    data: Json<SetDeactivatedData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult {
+    let admin = admin?;
+    not_self(&admin, data.id, "deactivate")?;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let result = sqlx::query!(
+        "UPDATE users SET deactivated = $2 WHERE id = $1",
+        data.id,
+        data.deactivated
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    if result.rows_affected() == 0 {
+        return Err(user_not_found());
+    }
+    if data.deactivated {
+        remove_sessions(&mut tx, data.id).await?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    log::info!(
+        "admin {} set user {} deactivated to {}",
+        admin.user_id,
+        data.id,
+        data.deactivated
+    );
+    Ok((Status::Ok, ApiResponse::success()))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteUserData {
+    pub id: Uuid,
+    // gets the files and folders of the user, they can't be deleted together with the account
+    pub transfer_to: Option<Uuid>,
+}
+
+#[delete("/user", format = "json", data = "<data>")]
+pub async fn delete_user(
+    data: Json<DeleteUserData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult {
+    let admin = admin?;
+    not_self(&admin, data.id, "delete")?;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    if let Some(transfer_to) = data.transfer_to {
+        if transfer_to == data.id {
+            return Err(ApiResponse::fail(
+                Status::BadRequest,
+                "files can't be transferred to the deleted user",
+                None,
+            ));
+        }
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM users WHERE id = $1) AS "exists!""#,
+            transfer_to
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+        if !exists {
+            return Err(ApiResponse::fail(
+                Status::NotFound,
+                "user to transfer the files to not found",
+                None,
+            ));
+        }
+
+        sqlx::query!(
+            "UPDATE folders SET owner_id = $2 WHERE owner_id = $1",
+            data.id,
+            transfer_to
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+        sqlx::query!(
+            "UPDATE files SET owner_id = $2 WHERE owner_id = $1",
+            data.id,
+            transfer_to
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    }
+
+    let result = sqlx::query!("DELETE FROM users WHERE id = $1", data.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            if let sqlx::Error::Database(db_err) = &e
+                && db_err.is_foreign_key_violation()
+            {
+                return ApiResponse::fail(
+                    Status::Conflict,
+                    "the user still owns files or folders, choose who gets them with transfer_to",
+                    None,
+                );
+            }
+            ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+        })?;
+    if result.rows_affected() == 0 {
+        return Err(user_not_found());
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    log::info!(
+        "admin {} deleted user {}, files went to {:?}",
+        admin.user_id,
+        data.id,
+        data.transfer_to
+    );
+    Ok((Status::Ok, ApiResponse::success()))
+}
@@ -0,0 +1,340 @@
+// maintenance commands that work without the web UI, e.g. when nobody can log in
+use clap::{Parser, Subcommand};
+use dotenvy::dotenv;
+use lempek_assets_backend::auth::endpoints::{insert_user, sessions_of, validate_new_user};
+use lempek_assets_backend::auth::lockout::clear_login_failures;
+use lempek_assets_backend::auth::users::{remove_sessions, replace_password};
+use lempek_assets_backend::db::{connect_db_without_migrations, MIGRATOR};
+use lempek_assets_backend::models::{ApiResponse, User};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use sqlx::{PgConnection, PgPool};
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::{env, fs, io};
+use uuid::Uuid;
+
+type CliResult<T = ()> = Result<T, Box<dyn Error>>;
+
+#[derive(Parser)]
+#[command(about = "Manage lempek-assets accounts and storage from the command line")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create an account, the password is asked for unless --password-stdin is given
+    CreateUser {
+        login: String,
+        #[arg(long)]
+        admin: bool,
+        /// Read the password from the first line of stdin
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Set a new password, end every session and lift the lockout of the account
+    ResetPassword {
+        login: String,
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Give admin rights to the user
+    GrantAdmin { login: String },
+    /// Take admin rights away from the user
+    RevokeAdmin { login: String },
+    /// List the sessions of the user
+    Sessions { login: String },
+    /// End one session of the user, or all of them without --id
+    RevokeSessions {
+        login: String,
+        #[arg(long)]
+        id: Option<Uuid>,
+    },
+    /// Apply the database migrations
+    Migrate,
+    /// Compare the files and folders in the database with FILES_DIR
+    CheckStorage,
+}
+
+#[rocket::main]
+async fn main() -> ExitCode {
+    dotenv().ok();
+    let cli = Cli::parse();
+
+    // only `migrate` changes the schema, the other commands expect it to be up to date
+    let pool = connect_db_without_migrations().await;
+    let result = match cli.command {
+        Command::CreateUser {
+            login,
+            admin,
+            password_stdin,
+        } => create_user(&pool, &login, admin, password_stdin).await,
+        Command::ResetPassword {
+            login,
+            password_stdin,
+        } => reset_password(&pool, &login, password_stdin).await,
+        Command::GrantAdmin { login } => set_admin(&pool, &login, true).await,
+        Command::RevokeAdmin { login } => set_admin(&pool, &login, false).await,
+        Command::Sessions { login } => list_sessions(&pool, &login).await,
+        Command::RevokeSessions { login, id } => revoke_sessions(&pool, &login, id).await,
+        Command::Migrate => migrate(&pool).await,
+        Command::CheckStorage => check_storage(&pool).await,
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// the helpers of the server answer with an API response, only its message is useful here
+fn api_error((_, response): (Status, Json<ApiResponse>)) -> Box<dyn Error> {
+    response
+        .into_inner()
+        .detail
+        .unwrap_or_else(|| "unknown error".to_string())
+        .into()
+}
+
+fn read_password(from_stdin: bool) -> CliResult<String> {
+    if from_stdin {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    let password = rpassword::prompt_password("password: ")?;
+    if rpassword::prompt_password("repeat password: ")? != password {
+        return Err("passwords don't match".into());
+    }
+    Ok(password)
+}
+
+async fn find_user(tx: &mut PgConnection, login: &str) -> CliResult<User> {
+    sqlx::query_as!(User, "SELECT * FROM users WHERE login = $1", login)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| format!("user {} not found", login).into())
+}
+
+async fn create_user(pool: &PgPool, login: &str, admin: bool, password_stdin: bool) -> CliResult {
+    let password = read_password(password_stdin)?;
+    let login = validate_new_user(login, &password).map_err(api_error)?;
+
+    let mut tx = pool.begin().await?;
+    let user = insert_user(&mut tx, login, &password, admin)
+        .await
+        .map_err(api_error)?;
+    // a setup token printed by a running server can't be used anymore
+    sqlx::query!("UPDATE settings SET setup_completed = TRUE")
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    println!(
+        "created {} {} ({})",
+        if user.admin { "admin" } else { "user" },
+        user.login,
+        user.id
+    );
+    Ok(())
+}
+
+async fn reset_password(pool: &PgPool, login: &str, password_stdin: bool) -> CliResult {
+    let mut tx = pool.begin().await?;
+    let user = find_user(&mut tx, login).await?;
+    let password = read_password(password_stdin)?;
+    replace_password(&mut tx, user.id, &password)
+        .await
+        .map_err(api_error)?;
+    tx.commit().await?;
+    clear_login_failures(pool, &user.login)
+        .await
+        .map_err(api_error)?;
+
+    println!(
+        "changed the password of {}, every session ended",
+        user.login
+    );
+    if user.deactivated {
+        println!("the account is deactivated, an admin has to activate it before it can log in");
+    }
+    Ok(())
+}
+
+async fn set_admin(pool: &PgPool, login: &str, admin: bool) -> CliResult {
+    let mut tx = pool.begin().await?;
+    let user = find_user(&mut tx, login).await?;
+    sqlx::query!("UPDATE users SET admin = $2 WHERE id = $1", user.id, admin)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    if admin {
+        println!("{} is an admin now", user.login);
+    } else {
+        println!("{} isn't an admin anymore", user.login);
+    }
+    Ok(())
+}
+
+async fn list_sessions(pool: &PgPool, login: &str) -> CliResult {
+    let mut conn = pool.acquire().await?;
+    let user = find_user(&mut conn, login).await?;
+    let sessions = sessions_of(pool, user.id, None).await.map_err(api_error)?;
+
+    if sessions.is_empty() {
+        println!("{} has no sessions", user.login);
+    }
+    for session in sessions {
+        let location = [session.city, session.region, session.country]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!(
+            "{}  created {}  expires {}  {}  {}",
+            session.id,
+            session.created_at.format("%Y-%m-%d %H:%M"),
+            session.expires_at.format("%Y-%m-%d %H:%M"),
+            if location.is_empty() { "-" } else { &location },
+            session.user_agent.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
+
+async fn revoke_sessions(pool: &PgPool, login: &str, id: Option<Uuid>) -> CliResult {
+    let mut tx = pool.begin().await?;
+    let user = find_user(&mut tx, login).await?;
+    let ended = match id {
+        Some(id) => sqlx::query!(
+            "DELETE FROM user_tokens WHERE user_id = $1 AND id = $2",
+            user.id,
+            id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected(),
+        None => remove_sessions(&mut tx, user.id).await.map_err(api_error)?,
+    };
+    tx.commit().await?;
+
+    if id.is_some() && ended == 0 {
+        return Err(format!("{} has no such session", user.login).into());
+    }
+    println!("ended {} sessions of {}", ended, user.login);
+    Ok(())
+}
+
+async fn migrate(pool: &PgPool) -> CliResult {
+    // the table doesn't exist before the first migration
+    let applied: Vec<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(pool)
+            .await
+            .unwrap_or_default();
+    MIGRATOR.run(pool).await?;
+
+    let mut count = 0;
+    for migration in MIGRATOR.iter().filter(|m| !applied.contains(&m.version)) {
+        println!("applied {} {}", migration.version, migration.description);
+        count += 1;
+    }
+    if count == 0 {
+        println!("the database is already up to date");
+    }
+    Ok(())
+}
+
+// everything under FILES_DIR, paths relative to it
+fn walk(
+    root: &Path,
+    dir: &Path,
+    dirs: &mut HashSet<PathBuf>,
+    files: &mut HashSet<PathBuf>,
+) -> CliResult {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let relative = path.strip_prefix(root)?.to_path_buf();
+        if path.is_dir() {
+            walk(root, &path, dirs, files)?;
+            dirs.insert(relative);
+        } else {
+            files.insert(relative);
+        }
+    }
+    Ok(())
+}
+
+// only reports, fixing it means deciding whether the database or the disk is right
+async fn check_storage(pool: &PgPool) -> CliResult {
+    let root = PathBuf::from(env::var("FILES_DIR").map_err(|_| "FILES_DIR must be set")?);
+
+    let folders = sqlx::query!(r#"SELECT get_folder_path(id) AS "path!" FROM folders"#)
+        .fetch_all(pool)
+        .await?;
+    let files = sqlx::query!(
+        r#"SELECT COALESCE(get_folder_path(folder_id) || '/', '') || name AS "path!", size FROM files"#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut disk_dirs = HashSet::new();
+    let mut disk_files = HashSet::new();
+    walk(&root, &root, &mut disk_dirs, &mut disk_files)?;
+
+    let mut problems = 0;
+    for folder in &folders {
+        if !disk_dirs.remove(Path::new(&folder.path)) {
+            println!("missing folder: {}", folder.path);
+            problems += 1;
+        }
+    }
+    for file in &files {
+        let path = PathBuf::from(&file.path);
+        if !disk_files.remove(&path) {
+            println!("missing file: {}", file.path);
+            problems += 1;
+            continue;
+        }
+        let size = fs::metadata(root.join(&path))?.len() as i64;
+        if size != file.size {
+            println!(
+                "wrong size: {} is {} bytes, the database says {}",
+                file.path, size, file.size
+            );
+            problems += 1;
+        }
+    }
+
+    let mut untracked = disk_dirs
+        .iter()
+        .map(|dir| format!("{}/", dir.display()))
+        .chain(disk_files.iter().map(|file| file.display().to_string()))
+        .collect::<Vec<_>>();
+    untracked.sort();
+    for path in &untracked {
+        println!("not in the database: {}", path);
+    }
+    problems += untracked.len();
+
+    println!(
+        "checked {} folders and {} files in {}",
+        folders.len(),
+        files.len(),
+        root.display()
+    );
+    if problems > 0 {
+        return Err(format!("found {} problems", problems).into());
+    }
+    Ok(())
+}
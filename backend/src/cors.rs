@@ -18,7 +18,14 @@ impl Fairing for Cors {
     async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
         response.set_header(Header::new("Access-Control-Allow-Origin", env::var("ALLOWED_ORIGIN").unwrap_or_default()));
         response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "content-type"));
+        response.set_header(Header::new(
+            "Access-Control-Allow-Headers",
+            "content-type, authorization, range, if-range, if-none-match, if-modified-since",
+        ));
+        response.set_header(Header::new(
+            "Access-Control-Expose-Headers",
+            "content-range, accept-ranges, etag, last-modified",
+        ));
         response.set_header(Header::new(
             "Access-Control-Allow-Methods",
             "GET, POST, OPTIONS, DELETE, PATCH",
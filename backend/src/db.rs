@@ -1,13 +1,21 @@
+use sqlx::migrate::Migrator;
 use sqlx::PgPool;
 use std::env;
 
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+// the server brings the schema up to date on every start
 pub async fn connect_db() -> PgPool {
-    let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPool::connect(&db_url)
-        .await
-        .expect("failed to connect to DB");
+    let pool = connect_db_without_migrations().await;
 
-    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
 
     pool
 }
+
+pub async fn connect_db_without_migrations() -> PgPool {
+    let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    PgPool::connect(&db_url)
+        .await
+        .expect("failed to connect to DB")
+}
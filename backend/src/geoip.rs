@@ -0,0 +1,94 @@
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+// shown next to the sessions of the user, all of it can be missing
+#[derive(Debug, Default, Clone)]
+pub struct Location {
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+}
+
+// lookups can't fail, a provider that can't tell where the address is returns an empty location
+pub trait GeoIpProvider: Send + Sync {
+    fn locate(&self, ip: IpAddr) -> Location;
+}
+
+// GeoLite2 City or any other database in the MaxMind format, read once at startup
+pub struct MaxMindGeoIp(Reader<Vec<u8>>);
+
+fn english_name(names: Option<&BTreeMap<&str, &str>>) -> Option<String> {
+    names
+        .and_then(|names| names.get("en"))
+        .map(|name| name.to_string())
+}
+
+impl GeoIpProvider for MaxMindGeoIp {
+    fn locate(&self, ip: IpAddr) -> Location {
+        match self.0.lookup::<geoip2::City>(ip) {
+            Ok(city) => Location {
+                country: english_name(city.country.and_then(|c| c.names).as_ref()),
+                region: english_name(
+                    city.subdivisions
+                        .and_then(|s| s.into_iter().next())
+                        .and_then(|s| s.names)
+                        .as_ref(),
+                ),
+                city: english_name(city.city.and_then(|c| c.names).as_ref()),
+            },
+            // private networks and addresses the database doesn't know about
+            Err(MaxMindDBError::AddressNotFoundError(_)) => Location::default(),
+            Err(e) => {
+                log::warn!("GeoIP lookup of {} failed: {}", ip, e);
+                Location::default()
+            }
+        }
+    }
+}
+
+pub struct NoGeoIp;
+
+impl GeoIpProvider for NoGeoIp {
+    fn locate(&self, _ip: IpAddr) -> Location {
+        Location::default()
+    }
+}
+
+pub struct GeoIp(Box<dyn GeoIpProvider>);
+
+impl GeoIp {
+    // GEOIP_PROVIDER is `maxmind` (default) or `none`, without the database file locations stay empty
+    pub fn from_env() -> Self {
+        let provider = std::env::var("GEOIP_PROVIDER").unwrap_or_else(|_| "maxmind".to_string());
+        match provider.as_str() {
+            "none" => Self(Box::new(NoGeoIp)),
+            "maxmind" => {
+                let path = std::env::var("GEOIP_DATABASE")
+                    .unwrap_or_else(|_| "GeoLite2-City.mmdb".to_string());
+                match Reader::open_readfile(&path) {
+                    Ok(reader) => Self(Box::new(MaxMindGeoIp(reader))),
+                    Err(e) => {
+                        log::warn!(
+                            "GeoIP database {} can't be read, session locations are disabled: {}",
+                            path,
+                            e
+                        );
+                        Self(Box::new(NoGeoIp))
+                    }
+                }
+            }
+            other => {
+                log::error!(
+                    "unknown GEOIP_PROVIDER {}, session locations are disabled",
+                    other
+                );
+                Self(Box::new(NoGeoIp))
+            }
+        }
+    }
+
+    pub fn locate(&self, ip: Option<IpAddr>) -> Location {
+        ip.map(|ip| self.0.locate(ip)).unwrap_or_default()
+    }
+}
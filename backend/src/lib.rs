@@ -0,0 +1,181 @@
+#[macro_use]
+extern crate rocket;
+mod assets;
+pub mod auth;
+mod cors;
+pub mod db;
+mod geoip;
+pub mod models;
+mod nginx;
+mod perms;
+mod proxy;
+mod serve;
+mod settings;
+mod setup;
+mod share;
+
+use crate::auth::authenticator::Authenticators;
+use crate::auth::oidc::OidcMetadata;
+use crate::cors::Cors;
+use crate::geoip::GeoIp;
+use crate::models::ApiResponse;
+use crate::proxy::TrustedProxies;
+use crate::setup::Setup;
+use chrono::Duration;
+use db::connect_db;
+use dotenvy::dotenv;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{Build, Config, Rocket};
+use sqlx::PgPool;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::{env, fs};
+
+const ACCESS_TOKEN_TIME: Duration = Duration::minutes(5);
+const REFRESH_TOKEN_TIME: Duration = Duration::days(30);
+// logins without "remember me", renewed with activity like the persistent ones
+const SESSION_TOKEN_TIME: Duration = Duration::hours(12);
+const PENDING_LOGIN_TIME: Duration = Duration::minutes(5);
+// a refresh token presented again within this time after rotation is a race between requests, not theft
+const REFRESH_REUSE_GRACE: Duration = Duration::seconds(30);
+// lockout after too many failed logins, doubled with every further failure
+const LOGIN_LOCKOUT_BASE: Duration = Duration::seconds(30);
+const LOGIN_LOCKOUT_MAX: Duration = Duration::hours(1);
+// failures are forgotten after this long without a new one
+const LOGIN_FAILURE_WINDOW: Duration = Duration::hours(24);
+// links for choosing a new password given out by admins
+const PASSWORD_RESET_TIME: Duration = Duration::hours(24);
+// how long a share link stays usable after its password was entered
+const SHARE_UNLOCK_TIME: Duration = Duration::hours(1);
+// the metadata and signing keys of the identity provider are discovered again after this long
+const OIDC_METADATA_TIME: Duration = Duration::hours(1);
+
+static FILES_DIR: OnceLock<String> = OnceLock::new();
+
+pub type ApiResult<T = (Status, Json<ApiResponse>)> = Result<T, (Status, Json<ApiResponse>)>;
+
+// shared by the server and the admin command line tool
+pub async fn rocket() -> Rocket<Build> {
+    dotenv().ok();
+    FILES_DIR.set(env::var("FILES_DIR").unwrap()).unwrap();
+
+    if !PathBuf::from(FILES_DIR.get().unwrap()).exists() {
+        fs::create_dir_all(FILES_DIR.get().unwrap()).unwrap();
+    }
+
+    let config = Config::figment();
+    let temp_dir: String = config
+        .extract_inner("temp_dir")
+        .unwrap_or_else(|_| "../tmp".to_string());
+    if !PathBuf::from(&temp_dir).exists() {
+        fs::create_dir_all(&temp_dir).expect("create temp dir");
+    }
+
+    build(connect_db().await).await
+}
+
+// the whole application on top of an already migrated database
+pub async fn build(pool: PgPool) -> Rocket<Build> {
+    // after `build` so the logger prints the setup token
+    rocket::build()
+        .manage(Setup::init(&pool).await)
+        .manage(pool)
+        .manage(Authenticators::from_env())
+        .manage(OidcMetadata::default())
+        .manage(TrustedProxies::from_env())
+        .manage(GeoIp::from_env())
+        .attach(Cors)
+        .mount(
+            "/api",
+            routes![
+                cors::options_handler,
+                auth::endpoints::login,
+                auth::endpoints::logout,
+                auth::endpoints::logout_all,
+                auth::endpoints::get_user,
+                auth::endpoints::get_user_all,
+                auth::endpoints::get_user_all_admin,
+                auth::endpoints::get_user_tokens,
+                auth::endpoints::get_user_tokens_admin,
+                auth::endpoints::remove_user_token,
+                auth::endpoints::remove_other_user_tokens,
+                auth::endpoints::remove_user_tokens_admin,
+                auth::endpoints::change_password,
+                auth::endpoints::change_username,
+                auth::endpoints::create_user,
+                auth::users::get_users,
+                auth::users::set_admin,
+                auth::users::reset_password,
+                auth::users::set_deactivated,
+                auth::users::delete_user,
+                auth::invites::get_invites,
+                auth::invites::create_invite,
+                auth::invites::delete_invite,
+                auth::invites::get_invite,
+                auth::invites::redeem_invite,
+                auth::password_reset::create_password_reset,
+                auth::password_reset::use_password_reset,
+                assets::create_folder,
+                assets::delete_folder,
+                assets::edit_folder,
+                assets::move_folder,
+                assets::get_folder,
+                assets::get_all_folders,
+                assets::get_folders_path,
+                assets::get_folders,
+
+                assets::upload_file,
+                assets::get_all_files,
+                assets::get_files,
+                assets::get_file_content,
+                assets::head_file_content,
+                assets::delete_file,
+                assets::edit_file,
+                assets::move_file,
+                nginx::auth_check,
+                nginx::get_asset,
+                auth::endpoints::login_two_factor,
+                auth::lockout::get_lockouts,
+                auth::lockout::unlock,
+                auth::tokens::get_access_tokens,
+                auth::tokens::create_access_token,
+                auth::tokens::revoke_access_token,
+                auth::two_factor::get_two_factor,
+                auth::two_factor::setup_two_factor,
+                auth::two_factor::enable_two_factor,
+                auth::two_factor::regenerate_recovery_codes,
+                auth::two_factor::disable_two_factor,
+                auth::passkeys::passkey_registration_options,
+                auth::passkeys::register_passkey,
+                auth::passkeys::get_passkeys,
+                auth::passkeys::rename_passkey,
+                auth::passkeys::revoke_passkey,
+                auth::passkeys::passkey_login_options,
+                auth::passkeys::login_passkey,
+                auth::oidc::oidc_login,
+                auth::oidc::oidc_callback,
+                settings::get_settings,
+                settings::edit_settings,
+                setup::get_setup,
+                setup::finish_setup,
+                perms::endpoints::get_folder_permissions,
+                perms::endpoints::get_user_permissions,
+                perms::endpoints::grant_permission,
+                perms::endpoints::edit_permission,
+                perms::endpoints::revoke_permission,
+                perms::groups::get_groups,
+                perms::groups::create_group,
+                perms::groups::rename_group,
+                perms::groups::delete_group,
+                perms::groups::get_group_members,
+                perms::groups::add_group_member,
+                perms::groups::remove_group_member,
+                share::create_share,
+                share::get_shares,
+                share::delete_share,
+                share::get_share,
+                share::unlock_share,
+            ],
+        )
+}
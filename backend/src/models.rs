@@ -92,10 +92,15 @@ pub struct User {
     pub id: Uuid,
     pub login: String,
     pub username: String,
-    pub password: String,
+    pub password: Option<String>,
     pub admin: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub totp_last_step: Option<i64>,
+    pub token_generation: i32,
+    pub deactivated: bool,
 }
 
 #[derive(Serialize, Deserialize, FromRow, Debug, Clone)]
@@ -111,12 +116,23 @@ pub struct UserToken {
     pub created_at: DateTime<Utc>,
 }
 
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[sqlx(type_name = "folder_visibility", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Inherit,
+    Public,
+    Private,
+}
+
 #[derive(FromRow, Serialize, Debug, Clone)]
 pub struct Folder {
     pub id: Uuid,
     pub parent_id: Option<Uuid>,
     pub owner_id: Uuid,
     pub name: String,
+    pub visibility: Visibility,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -132,12 +148,136 @@ pub struct File {
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(FromRow, Debug, Clone)]
+#[derive(FromRow, Serialize, Debug, Clone)]
 pub struct Permission {
     pub id: Uuid,
     pub folder_id: Option<Uuid>,
+    pub user_id: Option<Uuid>,
+    pub group_id: Option<Uuid>,
+    pub role: Option<Role>,
+    pub list: bool,
+    pub read: bool,
+    pub upload: bool,
+    pub rename: bool,
+    pub r#move: bool,
+    pub delete: bool,
+    pub share: bool,
+    pub manage_permissions: bool,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "permission_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Uploader,
+    Maintainer,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "token_scope", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    Read,
+    Upload,
+    Admin,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "login_failure_kind", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum LoginFailureKind {
+    Account,
+    Ip,
+}
+
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct LoginFailure {
+    pub kind: LoginFailureKind,
+    pub subject: String,
+    pub failures: i32,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub last_failure_at: DateTime<Utc>,
+}
+
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct AccessToken {
+    pub id: Uuid,
     pub user_id: Uuid,
+    pub name: String,
+    pub scope: TokenScope,
+    pub folder_id: Option<Uuid>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct Invite {
+    pub id: Uuid,
+    pub created_by: Option<Uuid>,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct InvitePermission {
+    pub id: Uuid,
+    pub invite_id: Uuid,
+    pub folder_id: Option<Uuid>,
+    pub role: Option<Role>,
+    pub list: bool,
     pub read: bool,
-    pub modify: bool,
-    pub edit: bool,
+    pub upload: bool,
+    pub rename: bool,
+    pub r#move: bool,
+    pub delete: bool,
+    pub share: bool,
+    pub manage_permissions: bool,
+}
+
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct Passkey {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "passkey_challenge_kind", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum PasskeyChallengeKind {
+    Registration,
+    Login,
+}
+
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct Settings {
+    pub require_admin_2fa: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct Group {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct ShareLink {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub file_id: Option<Uuid>,
+    pub folder_id: Option<Uuid>,
+    pub password: Option<String>,
+    pub max_downloads: Option<i32>,
+    pub downloads: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
 }
@@ -0,0 +1,189 @@
+use crate::auth::AuthUser;
+use crate::models::ApiResponse;
+use crate::perms::check_read_permission;
+use crate::serve::{serve_file, FileContent, Preconditions};
+use crate::ApiResult;
+use crate::FILES_DIR;
+use chrono::{DateTime, Utc};
+use rocket::http::{RawStr, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::{Either, Request, Response, State};
+use sqlx::{PgConnection, PgPool};
+use std::env;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+// nginx sends the path of the original request in this header when using auth_request
+pub struct OriginalUri(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for OriginalUri {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(OriginalUri(
+            request
+                .headers()
+                .get_one("X-Original-URI")
+                .map(|s| s.to_string()),
+        ))
+    }
+}
+
+pub struct AccelRedirect(String);
+
+impl<'r> Responder<'r, 'static> for AccelRedirect {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .raw_header("X-Accel-Redirect", self.0)
+            .ok()
+    }
+}
+
+pub struct AssetFile {
+    pub folder_id: Option<Uuid>,
+    pub updated_at: DateTime<Utc>,
+    pub segments: Vec<String>,
+}
+
+// splits "/folder/sub folder/file.png?v=1" into decoded path segments
+fn split_uri(uri: &str) -> ApiResult<Vec<String>> {
+    let path = uri.split(['?', '#']).next().unwrap_or_default();
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            RawStr::new(s)
+                .percent_decode()
+                .map(|s| s.to_string())
+                .map_err(|_| ApiResponse::fail(Status::BadRequest, "invalid path", None))
+        })
+        .collect::<ApiResult<Vec<_>>>()?;
+    if segments.is_empty()
+        || segments
+            .iter()
+            .any(|s| s == "." || s == ".." || s.contains(['/', '\\']))
+    {
+        return Err(ApiResponse::fail(Status::BadRequest, "invalid path", None));
+    }
+    Ok(segments)
+}
+
+pub async fn resolve_asset(tx: &mut PgConnection, segments: Vec<String>) -> ApiResult<AssetFile> {
+    let (file_name, folders) = segments.split_last().unwrap();
+
+    let folder_id = match folders.last() {
+        None => None,
+        Some(folder_name) => Some(
+            sqlx::query_scalar!(
+                "SELECT id FROM folders WHERE name = $1 AND get_folder_path(id) = $2",
+                folder_name,
+                folders.join("/")
+            )
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| {
+                ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+            })?
+            .ok_or_else(|| ApiResponse::fail(Status::NotFound, "file not found", None))?,
+        ),
+    };
+
+    let updated_at = sqlx::query_scalar!(
+        "SELECT updated_at FROM files WHERE name = $1 AND folder_id IS NOT DISTINCT FROM $2",
+        file_name,
+        folder_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+    .ok_or_else(|| ApiResponse::fail(Status::NotFound, "file not found", None))?;
+
+    Ok(AssetFile {
+        folder_id,
+        updated_at,
+        segments,
+    })
+}
+
+async fn authorize_asset(
+    segments: Vec<String>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<AssetFile> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let asset = resolve_asset(&mut tx, segments).await?;
+    check_read_permission(&mut tx, auth, asset.folder_id).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    Ok(asset)
+}
+
+// used by nginx `auth_request`, it only understands 2xx, 401 and 403 so everything else is a 403
+#[get("/auth/check")]
+pub async fn auth_check(uri: OriginalUri, pool: &State<PgPool>, auth: AuthUser) -> ApiResult {
+    let result = match uri.0.as_deref().map(split_uri) {
+        Some(Ok(segments)) => authorize_asset(segments, pool, auth).await,
+        Some(Err(e)) => Err(e),
+        None => Err(ApiResponse::fail(
+            Status::BadRequest,
+            "missing X-Original-URI header",
+            None,
+        )),
+    };
+
+    match result {
+        Ok(_) => Ok((Status::NoContent, ApiResponse::success())),
+        Err((status, e)) if status == Status::Unauthorized => Err((status, e)),
+        Err((_, e)) => Err((Status::Forbidden, e)),
+    }
+}
+
+// when ACCEL_REDIRECT_LOCATION is set nginx sends the file from its internal location,
+// otherwise the backend serves it by itself
+#[get("/asset/<path..>")]
+pub async fn get_asset(
+    path: PathBuf,
+    pre: Preconditions,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Either<AccelRedirect, FileContent>> {
+    let segments = path
+        .iter()
+        .map(|s| s.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    if segments.is_empty() {
+        return Err(ApiResponse::fail(Status::BadRequest, "invalid path", None));
+    }
+    let asset = authorize_asset(segments, pool, auth).await?;
+
+    if let Some(location) = env::var("ACCEL_REDIRECT_LOCATION")
+        .ok()
+        .filter(|l| !l.is_empty())
+    {
+        let encoded = asset
+            .segments
+            .iter()
+            .map(|s| RawStr::new(s).percent_encode().to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+        return Ok(Either::Left(AccelRedirect(format!(
+            "{}/{}",
+            location.trim_end_matches('/'),
+            encoded
+        ))));
+    }
+
+    let mut file_path = PathBuf::from(FILES_DIR.get().unwrap());
+    file_path.extend(&asset.segments);
+    Ok(Either::Right(
+        serve_file(&file_path, asset.updated_at, &pre).await?,
+    ))
+}
@@ -0,0 +1,427 @@
+use crate::auth::AuthUser;
+use crate::models::{ApiResponse, Permission, Role};
+use crate::perms::{check_can_manage, PermissionKind};
+use crate::ApiResult;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+fn map_fk_error(e: sqlx::Error) -> (Status, Json<ApiResponse>) {
+    if let sqlx::Error::Database(db_err) = &e
+        && db_err.is_foreign_key_violation()
+    {
+        ApiResponse::fail(Status::NotFound, "user, group or folder not found", None)
+    } else {
+        ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+    }
+}
+
+#[derive(Serialize, FromRow)]
+pub struct FolderPermissionData {
+    pub id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub login: Option<String>,
+    pub username: Option<String>,
+    pub group_id: Option<Uuid>,
+    pub group_name: Option<String>,
+    pub role: Option<Role>,
+    pub list: bool,
+    pub read: bool,
+    pub upload: bool,
+    pub rename: bool,
+    pub r#move: bool,
+    pub delete: bool,
+    pub share: bool,
+    pub manage_permissions: bool,
+}
+
+#[get("/permissions?<folder>")]
+pub async fn get_folder_permissions(
+    folder: Option<Uuid>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<Vec<FolderPermissionData>>> {
+    let auth = auth?;
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    check_can_manage(&mut tx, &auth, folder).await?;
+
+    let result = sqlx::query_as!(
+        FolderPermissionData,
+        r#"
+        SELECT p.id, p.user_id, u.login AS "login?", u.username AS "username?",
+               p.group_id, g.name AS "group_name?", p.role AS "role: Role",
+               p.list, p.read, p.upload, p.rename, p.move, p.delete, p.share, p.manage_permissions
+        FROM permissions p
+        LEFT JOIN users u ON u.id = p.user_id
+        LEFT JOIN groups g ON g.id = p.group_id
+        WHERE p.folder_id IS NOT DISTINCT FROM $1
+        ORDER BY p.group_id IS NOT NULL, LOWER(COALESCE(u.username, g.name))
+        "#,
+        folder
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(result))
+}
+
+#[derive(Serialize, FromRow)]
+pub struct UserPermissionData {
+    pub id: Uuid,
+    pub folder_id: Option<Uuid>,
+    pub path: Option<String>,
+    pub group_id: Option<Uuid>,
+    pub group_name: Option<String>,
+    pub role: Option<Role>,
+    pub list: bool,
+    pub read: bool,
+    pub upload: bool,
+    pub rename: bool,
+    pub r#move: bool,
+    pub delete: bool,
+    pub share: bool,
+    pub manage_permissions: bool,
+}
+
+// without `id` it lists permissions of the logged-in user, only admins can look up other users,
+// permissions given to the groups of the user are listed as well
+#[get("/permissions/user?<id>")]
+pub async fn get_user_permissions(
+    id: Option<Uuid>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<Vec<UserPermissionData>>> {
+    let auth = auth?;
+    let user_id = id.unwrap_or(auth.user_id);
+    if user_id != auth.user_id && !auth.admin {
+        return Err(ApiResponse::fail(
+            Status::Forbidden,
+            "you can only see your own permissions",
+            None,
+        ));
+    }
+
+    let result = sqlx::query_as!(
+        UserPermissionData,
+        r#"
+        SELECT p.id, p.folder_id, get_folder_path(p.folder_id) AS path,
+               p.group_id, g.name AS "group_name?", p.role AS "role: Role",
+               p.list, p.read, p.upload, p.rename, p.move, p.delete, p.share, p.manage_permissions
+        FROM permissions p
+        LEFT JOIN groups g ON g.id = p.group_id
+        WHERE p.user_id = $1
+           OR p.group_id IN (SELECT group_id FROM group_members WHERE user_id = $1)
+        ORDER BY path NULLS FIRST, p.group_id NULLS FIRST
+        "#,
+        user_id
+    )
+    .fetch_all(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(result))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct PermissionFlags {
+    pub list: bool,
+    pub read: bool,
+    pub upload: bool,
+    pub rename: bool,
+    pub r#move: bool,
+    pub delete: bool,
+    pub share: bool,
+    pub manage_permissions: bool,
+}
+
+impl From<Role> for PermissionFlags {
+    fn from(role: Role) -> Self {
+        Self {
+            list: role.allows(PermissionKind::List),
+            read: role.allows(PermissionKind::Read),
+            upload: role.allows(PermissionKind::Upload),
+            rename: role.allows(PermissionKind::Rename),
+            r#move: role.allows(PermissionKind::Move),
+            delete: role.allows(PermissionKind::Delete),
+            share: role.allows(PermissionKind::Share),
+            manage_permissions: role.allows(PermissionKind::ManagePermissions),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GrantPermissionData {
+    pub user_id: Option<Uuid>,
+    pub group_id: Option<Uuid>,
+    pub folder_id: Option<Uuid>,
+    pub role: Option<Role>,
+    #[serde(flatten)]
+    pub flags: PermissionFlags,
+}
+
+// creates the permission or overwrites all of its flags if it already exists,
+// when `role` is given the flags come from the role
+#[post("/permission", format = "json", data = "<data>")]
+pub async fn grant_permission(
+    data: Json<GrantPermissionData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<Permission>> {
+    let auth = auth?;
+    let data = data.into_inner();
+    let flags = data.role.map(PermissionFlags::from).unwrap_or(data.flags);
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    check_can_manage(&mut tx, &auth, data.folder_id).await?;
+
+    let permission = match (data.user_id, data.group_id) {
+        (Some(user_id), None) => sqlx::query_as!(
+            Permission,
+            r#"
+            INSERT INTO permissions (user_id, folder_id, role, list, read, upload, rename, move, delete, share, manage_permissions)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (user_id, folder_id) WHERE user_id IS NOT NULL
+            DO UPDATE SET role = $3, list = $4, read = $5, upload = $6, rename = $7, move = $8, delete = $9,
+                          share = $10, manage_permissions = $11
+            RETURNING id, folder_id, user_id, group_id, role AS "role: Role",
+                      list, read, upload, rename, move, delete, share, manage_permissions
+            "#,
+            user_id,
+            data.folder_id,
+            data.role as Option<Role>,
+            flags.list,
+            flags.read,
+            flags.upload,
+            flags.rename,
+            flags.r#move,
+            flags.delete,
+            flags.share,
+            flags.manage_permissions,
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(map_fk_error)?,
+        (None, Some(group_id)) => sqlx::query_as!(
+            Permission,
+            r#"
+            INSERT INTO permissions (group_id, folder_id, role, list, read, upload, rename, move, delete, share, manage_permissions)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (group_id, folder_id) WHERE group_id IS NOT NULL
+            DO UPDATE SET role = $3, list = $4, read = $5, upload = $6, rename = $7, move = $8, delete = $9,
+                          share = $10, manage_permissions = $11
+            RETURNING id, folder_id, user_id, group_id, role AS "role: Role",
+                      list, read, upload, rename, move, delete, share, manage_permissions
+            "#,
+            group_id,
+            data.folder_id,
+            data.role as Option<Role>,
+            flags.list,
+            flags.read,
+            flags.upload,
+            flags.rename,
+            flags.r#move,
+            flags.delete,
+            flags.share,
+            flags.manage_permissions,
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(map_fk_error)?,
+        _ => {
+            return Err(ApiResponse::fail(
+                Status::BadRequest,
+                "either user_id or group_id must be provided",
+                None,
+            ));
+        }
+    };
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(permission))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct EditPermissionFlags {
+    pub list: Option<bool>,
+    pub read: Option<bool>,
+    pub upload: Option<bool>,
+    pub rename: Option<bool>,
+    pub r#move: Option<bool>,
+    pub delete: Option<bool>,
+    pub share: Option<bool>,
+    pub manage_permissions: Option<bool>,
+}
+
+impl From<PermissionFlags> for EditPermissionFlags {
+    fn from(flags: PermissionFlags) -> Self {
+        Self {
+            list: Some(flags.list),
+            read: Some(flags.read),
+            upload: Some(flags.upload),
+            rename: Some(flags.rename),
+            r#move: Some(flags.r#move),
+            delete: Some(flags.delete),
+            share: Some(flags.share),
+            manage_permissions: Some(flags.manage_permissions),
+        }
+    }
+}
+
+impl EditPermissionFlags {
+    fn is_empty(&self) -> bool {
+        [
+            self.list,
+            self.read,
+            self.upload,
+            self.rename,
+            self.r#move,
+            self.delete,
+            self.share,
+            self.manage_permissions,
+        ]
+        .iter()
+        .all(Option::is_none)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EditPermissionData {
+    pub id: Uuid,
+    pub role: Option<Role>,
+    #[serde(flatten)]
+    pub flags: EditPermissionFlags,
+}
+
+// setting a role overwrites all flags, changing a flag by hand removes the role
+#[patch("/permission", format = "json", data = "<data>")]
+pub async fn edit_permission(
+    data: Json<EditPermissionData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<Permission>> {
+    let auth = auth?;
+    let data = data.into_inner();
+    let flags = match data.role {
+        Some(role) => EditPermissionFlags::from(PermissionFlags::from(role)),
+        None => data.flags,
+    };
+    if flags.is_empty() {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "nothing to change",
+            None,
+        ));
+    }
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let folder_id = sqlx::query_scalar!("SELECT folder_id FROM permissions WHERE id = $1", data.id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+        .ok_or_else(|| ApiResponse::fail(Status::NotFound, "permission not found", None))?;
+
+    check_can_manage(&mut tx, &auth, folder_id).await?;
+
+    let permission = sqlx::query_as!(
+        Permission,
+        r#"
+        UPDATE permissions
+        SET role               = $2,
+            list               = COALESCE($3, list),
+            read               = COALESCE($4, read),
+            upload             = COALESCE($5, upload),
+            rename             = COALESCE($6, rename),
+            move               = COALESCE($7, move),
+            delete             = COALESCE($8, delete),
+            share              = COALESCE($9, share),
+            manage_permissions = COALESCE($10, manage_permissions)
+        WHERE id = $1
+        RETURNING id, folder_id, user_id, group_id, role AS "role: Role",
+                  list, read, upload, rename, move, delete, share, manage_permissions
+        "#,
+        data.id,
+        data.role as Option<Role>,
+        flags.list,
+        flags.read,
+        flags.upload,
+        flags.rename,
+        flags.r#move,
+        flags.delete,
+        flags.share,
+        flags.manage_permissions,
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(permission))
+}
+
+#[derive(Deserialize)]
+pub struct RevokePermissionData {
+    pub id: Uuid,
+}
+
+#[delete("/permission", format = "json", data = "<data>")]
+pub async fn revoke_permission(
+    data: Json<RevokePermissionData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult {
+    let auth = auth?;
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let folder_id = sqlx::query_scalar!("SELECT folder_id FROM permissions WHERE id = $1", data.id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+        .ok_or_else(|| ApiResponse::fail(Status::NotFound, "permission not found", None))?;
+
+    check_can_manage(&mut tx, &auth, folder_id).await?;
+
+    sqlx::query!("DELETE FROM permissions WHERE id = $1", data.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok((
+        Status::NoContent,
+        ApiResponse::success_with("revoked permission"),
+    ))
+}
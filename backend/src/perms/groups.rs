@@ -0,0 +1,231 @@
+use crate::auth::{AuthAdminUser, AuthUser};
+use crate::models::{ApiResponse, Group};
+use crate::ApiResult;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+fn map_group_error(e: sqlx::Error) -> (Status, Json<ApiResponse>) {
+    if let sqlx::Error::Database(db_err) = &e
+        && db_err.is_unique_violation()
+    {
+        ApiResponse::fail(
+            Status::Conflict,
+            "group with this name already exists",
+            None,
+        )
+    } else {
+        ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+    }
+}
+
+fn validate_group_name(name: &str) -> ApiResult<&str> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "group name must be provided",
+            None,
+        ));
+    }
+    Ok(name)
+}
+
+// every logged-in user can see the groups, folder owners need them to give permissions
+#[get("/groups")]
+pub async fn get_groups(pool: &State<PgPool>, auth: AuthUser) -> ApiResult<Json<Vec<Group>>> {
+    let _auth = auth?;
+    let groups = sqlx::query_as!(Group, "SELECT * FROM groups ORDER BY LOWER(name)")
+        .fetch_all(pool.inner())
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(groups))
+}
+
+#[derive(Deserialize)]
+pub struct NewGroupData {
+    pub name: String,
+}
+
+#[post("/group", format = "json", data = "<data>")]
+pub async fn create_group(
+    data: Json<NewGroupData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult<Json<Group>> {
+    let _admin = admin?;
+    let name = validate_group_name(&data.name)?;
+
+    let group = sqlx::query_as!(
+        Group,
+        "INSERT INTO groups (name) VALUES ($1) RETURNING *",
+        name
+    )
+    .fetch_one(pool.inner())
+    .await
+    .map_err(map_group_error)?;
+
+    Ok(Json(group))
+}
+
+#[derive(Deserialize)]
+pub struct RenameGroupData {
+    pub id: Uuid,
+    pub name: String,
+}
+
+#[patch("/group", format = "json", data = "<data>")]
+pub async fn rename_group(
+    data: Json<RenameGroupData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult<Json<Group>> {
+    let _admin = admin?;
+    let name = validate_group_name(&data.name)?;
+
+    let group = sqlx::query_as!(
+        Group,
+        "UPDATE groups SET name = $2 WHERE id = $1 RETURNING *",
+        data.id,
+        name
+    )
+    .fetch_optional(pool.inner())
+    .await
+    .map_err(map_group_error)?
+    .ok_or_else(|| ApiResponse::fail(Status::NotFound, "group not found", None))?;
+
+    Ok(Json(group))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteGroupData {
+    pub id: Uuid,
+}
+
+// permissions given to the group are removed together with it
+#[delete("/group", format = "json", data = "<data>")]
+pub async fn delete_group(
+    data: Json<DeleteGroupData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult {
+    let _admin = admin?;
+
+    let result = sqlx::query!("DELETE FROM groups WHERE id = $1", data.id)
+        .execute(pool.inner())
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiResponse::fail(Status::NotFound, "group not found", None));
+    }
+
+    Ok((
+        Status::NoContent,
+        ApiResponse::success_with("deleted group"),
+    ))
+}
+
+#[derive(Serialize, FromRow)]
+pub struct GroupMemberData {
+    pub user_id: Uuid,
+    pub login: String,
+    pub username: String,
+    pub added_at: DateTime<Utc>,
+}
+
+#[get("/group/members?<id>")]
+pub async fn get_group_members(
+    id: Uuid,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult<Json<Vec<GroupMemberData>>> {
+    let _admin = admin?;
+
+    let members = sqlx::query_as!(
+        GroupMemberData,
+        r#"
+        SELECT u.id AS user_id, u.login, u.username, m.created_at AS added_at
+        FROM group_members m
+        INNER JOIN users u ON u.id = m.user_id
+        WHERE m.group_id = $1
+        ORDER BY LOWER(u.username)
+        "#,
+        id
+    )
+    .fetch_all(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(members))
+}
+
+#[derive(Deserialize)]
+pub struct GroupMemberChangeData {
+    pub group_id: Uuid,
+    pub user_id: Uuid,
+}
+
+#[post("/group/member", format = "json", data = "<data>")]
+pub async fn add_group_member(
+    data: Json<GroupMemberChangeData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult {
+    let _admin = admin?;
+
+    sqlx::query!(
+        "INSERT INTO group_members (group_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        data.group_id,
+        data.user_id
+    )
+    .execute(pool.inner())
+    .await
+    .map_err(|e| {
+        if let sqlx::Error::Database(db_err) = &e
+            && db_err.is_foreign_key_violation()
+        {
+            ApiResponse::fail(Status::NotFound, "user or group not found", None)
+        } else {
+            ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+        }
+    })?;
+
+    Ok((Status::Ok, ApiResponse::success_with("added user to group")))
+}
+
+#[delete("/group/member", format = "json", data = "<data>")]
+pub async fn remove_group_member(
+    data: Json<GroupMemberChangeData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult {
+    let _admin = admin?;
+
+    let result = sqlx::query!(
+        "DELETE FROM group_members WHERE group_id = $1 AND user_id = $2",
+        data.group_id,
+        data.user_id
+    )
+    .execute(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiResponse::fail(
+            Status::NotFound,
+            "user is not in this group",
+            None,
+        ));
+    }
+
+    Ok((
+        Status::NoContent,
+        ApiResponse::success_with("removed user from group"),
+    ))
+}
@@ -0,0 +1,196 @@
+use crate::auth::tokens::TokenAccess;
+use crate::auth::{AuthUser, UserData};
+use crate::models::{ApiResponse, Role, TokenScope};
+use rocket::http::Status;
+use sqlx::PgConnection;
+use uuid::Uuid;
+use crate::ApiResult;
+
+pub mod endpoints;
+pub mod groups;
+
+pub enum PermissionKind {
+    List,
+    Read,
+    Upload,
+    Rename,
+    Move,
+    Delete,
+    Share,
+    ManagePermissions,
+}
+
+impl PermissionKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PermissionKind::List => "list",
+            PermissionKind::Read => "read",
+            PermissionKind::Upload => "upload",
+            PermissionKind::Rename => "rename",
+            PermissionKind::Move => "move",
+            PermissionKind::Delete => "delete",
+            PermissionKind::Share => "share",
+            PermissionKind::ManagePermissions => "manage_permissions",
+        }
+    }
+}
+
+// built-in roles are presets of the permission flags that can be given on any folder,
+// managing permissions is never part of a role and has to be given by hand
+impl Role {
+    pub fn allows(&self, permission: PermissionKind) -> bool {
+        match permission {
+            PermissionKind::List | PermissionKind::Read => true,
+            PermissionKind::Upload => matches!(self, Role::Uploader | Role::Maintainer),
+            PermissionKind::Rename
+            | PermissionKind::Move
+            | PermissionKind::Delete
+            | PermissionKind::Share => matches!(self, Role::Maintainer),
+            PermissionKind::ManagePermissions => false,
+        }
+    }
+}
+
+// access tokens only cover a subset of what their owner can do
+impl TokenScope {
+    pub fn allows(&self, permission: &PermissionKind) -> bool {
+        match self {
+            TokenScope::Read => matches!(permission, PermissionKind::List | PermissionKind::Read),
+            TokenScope::Upload => matches!(
+                permission,
+                PermissionKind::List | PermissionKind::Read | PermissionKind::Upload
+            ),
+            TokenScope::Admin => true,
+        }
+    }
+}
+
+async fn check_token_scope(
+    tx: &mut PgConnection,
+    token: &TokenAccess,
+    folder_id: Option<Uuid>,
+    permission: &PermissionKind,
+) -> ApiResult<()> {
+    if !token.scope.allows(permission) {
+        return Err(ApiResponse::fail(
+            Status::Forbidden,
+            format!(
+                "access token scope doesn't allow to {}",
+                permission.as_str()
+            ),
+            None,
+        ));
+    }
+
+    if let Some(token_folder) = token.folder_id {
+        let within =
+            sqlx::query_scalar!("SELECT folder_is_within($1, $2)", folder_id, token_folder)
+                .fetch_one(tx)
+                .await
+                .map_err(|e| {
+                    ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+                })?
+                .unwrap_or(false);
+        if !within {
+            return Err(ApiResponse::fail(
+                Status::Forbidden,
+                "access token is limited to a different folder",
+                None,
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub async fn check_permission<'a>(
+    tx: &mut PgConnection,
+    user: &UserData,
+    folder_id: Option<Uuid>,
+    permission: PermissionKind,
+) -> ApiResult<()> {
+    if let Some(token) = &user.token {
+        check_token_scope(tx, token, folder_id, &permission).await?;
+    }
+
+    if user.admin {
+        return Ok(());
+    }
+
+    // permissions are inherited from the nearest folder up the tree that has any,
+    // grants of the user and of all groups the user is in are combined
+    let query = format!(
+        "SELECT {} FROM get_effective_permission($1, $2)",
+        permission.as_str()
+    );
+
+    let value: Option<bool> = sqlx::query_scalar(&query)
+        .bind(user.user_id)
+        .bind(folder_id)
+        .fetch_optional(tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    if value.is_some_and(|v| v) {
+        Ok(())
+    } else {
+        Err(ApiResponse::fail(
+            Status::Forbidden,
+            &format!("no permissions to {}", permission.as_str()),
+            None,
+        ))
+    }
+}
+
+// permissions of a folder can be managed by admins, the owner of that folder
+// and anyone who was given the right to manage them
+pub async fn check_can_manage(
+    tx: &mut PgConnection,
+    user: &UserData,
+    folder_id: Option<Uuid>,
+) -> ApiResult<()> {
+    user.require_full_access()?;
+    if user.admin {
+        return Ok(());
+    }
+
+    if let Some(folder_id) = folder_id {
+        let owner_id = sqlx::query_scalar!("SELECT owner_id FROM folders WHERE id = $1", folder_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| {
+                ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+            })?
+            .ok_or_else(|| ApiResponse::fail(Status::NotFound, "folder not found", None))?;
+
+        if owner_id == user.user_id {
+            return Ok(());
+        }
+    }
+
+    check_permission(tx, user, folder_id, PermissionKind::ManagePermissions).await
+}
+
+pub async fn is_folder_public(tx: &mut PgConnection, folder_id: Option<Uuid>) -> ApiResult<bool> {
+    Ok(
+        sqlx::query_scalar!("SELECT folder_is_public($1)", folder_id)
+            .fetch_one(tx)
+            .await
+            .map_err(|e| {
+                ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+            })?
+            .unwrap_or(false),
+    )
+}
+
+// public folders can be read by anyone, even without logging in
+pub async fn check_read_permission(
+    tx: &mut PgConnection,
+    auth: AuthUser,
+    folder_id: Option<Uuid>,
+) -> ApiResult<()> {
+    if is_folder_public(tx, folder_id).await? {
+        return Ok(());
+    }
+    let user = auth?;
+    check_permission(tx, &user, folder_id, PermissionKind::Read).await
+}
@@ -0,0 +1,260 @@
+use ipnet::IpNet;
+use rocket::Request;
+use std::net::{IpAddr, SocketAddr};
+
+// the header the proxies set, the other one is ignored because a client could have sent it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyHeader {
+    XForwardedFor,
+    Forwarded,
+}
+
+// reverse proxies allowed to tell the address of the client, set with TRUSTED_PROXIES
+// and TRUSTED_PROXY_HEADER
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+    header: ProxyHeader,
+}
+
+impl TrustedProxies {
+    // comma separated networks or single addresses, the backend listens on localhost behind nginx by default
+    pub fn from_env() -> Self {
+        let header = std::env::var("TRUSTED_PROXY_HEADER").unwrap_or_default();
+        let header = match header.trim().to_ascii_lowercase().as_str() {
+            "" | "x-forwarded-for" => ProxyHeader::XForwardedFor,
+            "forwarded" => ProxyHeader::Forwarded,
+            _ => {
+                log::error!(
+                    "TRUSTED_PROXY_HEADER must be X-Forwarded-For or Forwarded, not {}",
+                    header
+                );
+                ProxyHeader::XForwardedFor
+            }
+        };
+
+        let value = std::env::var("TRUSTED_PROXIES").unwrap_or_default();
+        if value.trim().is_empty() {
+            return Self {
+                networks: vec!["127.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+                header,
+            };
+        }
+
+        let mut networks = Vec::new();
+        for entry in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+            {
+                Ok(network) => networks.push(network),
+                Err(_) => log::error!("TRUSTED_PROXIES contains an invalid network: {}", entry),
+            }
+        }
+        Self { networks, header }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+
+    // addresses are added to the end of the chain by every proxy, so it's read from the right
+    // and the first address that isn't a trusted proxy is the client, everything before it could be made up
+    pub fn client_ip(&self, request: &Request<'_>) -> Option<IpAddr> {
+        let peer = request.remote()?.ip().to_canonical();
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+
+        let chain = match self.header {
+            ProxyHeader::XForwardedFor => x_forwarded_for_chain(request),
+            ProxyHeader::Forwarded => forwarded_chain(request),
+        };
+        let Some(chain) = chain else {
+            return Some(peer);
+        };
+
+        let mut client = peer;
+        for node in chain.iter().rev() {
+            // "unknown" or an obfuscated identifier, there's no way to tell who sent it,
+            // so the last trusted proxy is as close to the client as we can get
+            let Some(ip) = node else {
+                break;
+            };
+            client = ip.to_canonical();
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+// `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` or `[2001:db8::1]:80`, quotes are already removed
+fn parse_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            value
+                .strip_prefix('[')
+                .and_then(|v| v.strip_suffix(']'))
+                .and_then(|v| v.parse().ok())
+        })
+}
+
+// headers can be split into many lines, each line is one more part of the same list
+fn header_list<'r>(request: &'r Request<'_>, name: &str) -> Vec<&'r str> {
+    request
+        .headers()
+        .get(name)
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+fn x_forwarded_for_chain(request: &Request<'_>) -> Option<Vec<Option<IpAddr>>> {
+    let values = header_list(request, "X-Forwarded-For");
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.into_iter().map(parse_node).collect())
+}
+
+// RFC 7239, e.g. `Forwarded: for=192.0.2.60;proto=https, for="[2001:db8::17]:4711"`
+fn forwarded_chain(request: &Request<'_>) -> Option<Vec<Option<IpAddr>>> {
+    let values = header_list(request, "Forwarded");
+    if values.is_empty() {
+        return None;
+    }
+    Some(
+        values
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, value)| parse_node(value.trim().trim_matches('"')))
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    const CLIENT: &str = "203.0.113.7";
+
+    fn proxies(header: ProxyHeader) -> TrustedProxies {
+        TrustedProxies {
+            networks: vec![
+                "127.0.0.0/8".parse().unwrap(),
+                "10.0.0.0/8".parse().unwrap(),
+            ],
+            header,
+        }
+    }
+
+    fn client_ip(
+        proxies: &TrustedProxies,
+        peer: &str,
+        headers: &[(&'static str, &'static str)],
+    ) -> IpAddr {
+        let client = Client::untracked(rocket::build()).unwrap();
+        let mut request = client
+            .get("/")
+            .remote(SocketAddr::new(peer.parse().unwrap(), 4000));
+        for (name, value) in headers {
+            request.add_header(Header::new(*name, *value));
+        }
+        proxies.client_ip(request.inner()).unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let proxies = proxies(ProxyHeader::XForwardedFor);
+        let forwarded = [("X-Forwarded-For", "198.51.100.1")];
+        assert_eq!(client_ip(&proxies, CLIENT, &forwarded), ip(CLIENT));
+    }
+
+    #[test]
+    fn trusted_peer_without_header_is_the_client() {
+        let proxies = proxies(ProxyHeader::XForwardedFor);
+        assert_eq!(client_ip(&proxies, "127.0.0.1", &[]), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn spoofed_addresses_before_the_client_are_ignored() {
+        let proxies = proxies(ProxyHeader::XForwardedFor);
+        let forwarded = [("X-Forwarded-For", "198.51.100.1, 203.0.113.7, 10.0.0.2")];
+        assert_eq!(client_ip(&proxies, "127.0.0.1", &forwarded), ip(CLIENT));
+        // a trusted address made up by the client is never reached
+        let forwarded = [("X-Forwarded-For", "10.0.0.9, 203.0.113.7")];
+        assert_eq!(client_ip(&proxies, "127.0.0.1", &forwarded), ip(CLIENT));
+    }
+
+    #[test]
+    fn chain_split_into_many_headers_is_read_in_order() {
+        let proxies = proxies(ProxyHeader::XForwardedFor);
+        let forwarded = [
+            ("X-Forwarded-For", "198.51.100.1"),
+            ("X-Forwarded-For", "203.0.113.7, 10.0.0.2"),
+        ];
+        assert_eq!(client_ip(&proxies, "127.0.0.1", &forwarded), ip(CLIENT));
+    }
+
+    #[test]
+    fn unparseable_hop_stops_at_the_last_trusted_address() {
+        let proxies = proxies(ProxyHeader::XForwardedFor);
+        let forwarded = [("X-Forwarded-For", "203.0.113.7, unknown, 10.0.0.2")];
+        assert_eq!(client_ip(&proxies, "127.0.0.1", &forwarded), ip("10.0.0.2"));
+        let forwarded = [("X-Forwarded-For", "203.0.113.7, unknown")];
+        assert_eq!(
+            client_ip(&proxies, "127.0.0.1", &forwarded),
+            ip("127.0.0.1")
+        );
+    }
+
+    #[test]
+    fn only_the_configured_header_is_read() {
+        let headers = [
+            ("X-Forwarded-For", "198.51.100.1"),
+            (
+                "Forwarded",
+                "for=\"[2001:db8::17]:4711\";proto=https, for=10.0.0.2",
+            ),
+        ];
+        let x_forwarded_for = proxies(ProxyHeader::XForwardedFor);
+        assert_eq!(
+            client_ip(&x_forwarded_for, "127.0.0.1", &headers),
+            ip("198.51.100.1")
+        );
+        let forwarded = proxies(ProxyHeader::Forwarded);
+        assert_eq!(
+            client_ip(&forwarded, "127.0.0.1", &headers),
+            ip("2001:db8::17")
+        );
+        assert_eq!(
+            client_ip(&forwarded, "127.0.0.1", &[("X-Forwarded-For", CLIENT)]),
+            ip("127.0.0.1")
+        );
+    }
+
+    #[test]
+    fn parses_nodes_with_ports() {
+        assert_eq!(parse_node("203.0.113.7:80"), Some(ip(CLIENT)));
+        assert_eq!(parse_node("[2001:db8::1]:80"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("_hidden"), None);
+    }
+}
@@ -0,0 +1,472 @@
+use crate::models::ApiResponse;
+use crate::ApiResult;
+use chrono::{DateTime, Utc};
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::tokio::fs::File;
+use rocket::tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt, ReadBuf, SeekFrom};
+use rocket::{Request, Response};
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+// headers that decide if we send the whole file, a part of it or nothing at all
+#[derive(Debug, Default)]
+pub struct Preconditions {
+    range: Option<String>,
+    if_range: Option<String>,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    head: bool,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Preconditions {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let get = |name: &str| headers.get_one(name).map(|s| s.to_string());
+        Outcome::Success(Preconditions {
+            range: get("Range"),
+            if_range: get("If-Range"),
+            if_none_match: get("If-None-Match"),
+            if_modified_since: get("If-Modified-Since"),
+            head: request.method() == Method::Head,
+        })
+    }
+}
+
+// reads at most `remaining` bytes from the current position of the file
+struct FileWindow {
+    file: File,
+    remaining: u64,
+}
+
+impl AsyncRead for FileWindow {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.remaining == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let max = buf.remaining().min(self.remaining as usize);
+        let mut window = ReadBuf::new(buf.initialize_unfilled_to(max));
+        ready!(Pin::new(&mut self.file).poll_read(cx, &mut window))?;
+        let read = window.filled().len();
+        buf.advance(read);
+        self.remaining -= read as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// rocket only seeks a body to find out its size and we always give it the size upfront
+impl AsyncSeek for FileWindow {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.file).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.file).poll_complete(cx)
+    }
+}
+
+pub struct FileContent {
+    status: Status,
+    content_type: ContentType,
+    headers: Vec<Header<'static>>,
+    body: Option<FileWindow>,
+    from_start: bool,
+}
+
+impl FileContent {
+    // true when file data starting at the first byte is sent, so seeking in an already started
+    // download, HEAD requests, 304 and 416 don't count
+    pub fn counts_as_download(&self) -> bool {
+        self.from_start
+    }
+}
+
+impl<'r> Responder<'r, 'static> for FileContent {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.status(self.status).header(self.content_type);
+        for header in self.headers {
+            response.header(header);
+        }
+        if let Some(body) = self.body {
+            response.sized_body(Some(body.remaining as usize), body);
+        }
+        response.ok()
+    }
+}
+
+// uploaded files that the browser would run as a page or script on our origin, together with
+// the session cookies, they are only ever downloaded
+fn is_active_content(content_type: &ContentType) -> bool {
+    let sub = content_type.sub().as_str().to_ascii_lowercase();
+    matches!(
+        sub.as_str(),
+        "html" | "xml" | "javascript" | "ecmascript" | "xsl"
+    ) || sub.ends_with("+xml")
+}
+
+fn http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|d| d.with_timezone(&Utc))
+}
+
+// ETags are compared weakly, it's only used for caching and the W/ prefix doesn't matter there
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.trim() == "*"
+        || header
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag)
+}
+
+fn not_modified(pre: &Preconditions, etag: &str, modified: &DateTime<Utc>) -> bool {
+    // If-None-Match takes precedence, If-Modified-Since is ignored when it's present
+    if let Some(if_none_match) = &pre.if_none_match {
+        return etag_matches(if_none_match, etag);
+    }
+    if let Some(since) = pre.if_modified_since.as_deref().and_then(parse_http_date) {
+        return modified.timestamp() <= since.timestamp();
+    }
+    false
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+fn parse_range(pre: &Preconditions, size: u64, etag: &str, modified: &DateTime<Utc>) -> ByteRange {
+    let Some(range) = &pre.range else {
+        return ByteRange::Full;
+    };
+    // range only applies if the client still has the same version of the file
+    if let Some(if_range) = &pre.if_range {
+        let same = match parse_http_date(if_range) {
+            Some(date) => date.timestamp() == modified.timestamp(),
+            None => if_range.trim() == etag,
+        };
+        if !same {
+            return ByteRange::Full;
+        }
+    }
+    // invalid or multipart ranges are ignored and the whole file is sent
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return ByteRange::Full,
+    };
+    if size == 0 || start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+pub async fn serve_file(
+    path: &Path,
+    modified: DateTime<Utc>,
+    pre: &Preconditions,
+) -> ApiResult<FileContent> {
+    let mut file = File::open(path)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::NotFound, "file not found", Some(&e)))?;
+    let size = file
+        .metadata()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "file error", Some(&e)))?
+        .len();
+
+    let etag = format!("\"{:x}-{:x}\"", size, modified.timestamp_micros());
+    let content_type = path
+        .extension()
+        .and_then(|ext| ContentType::from_extension(&ext.to_string_lossy()))
+        .unwrap_or(ContentType::Binary);
+    let mut headers = vec![
+        Header::new("ETag", etag.clone()),
+        Header::new("Last-Modified", http_date(&modified)),
+        Header::new("Accept-Ranges", "bytes"),
+        Header::new("Cache-Control", "private, no-cache"),
+        Header::new("X-Content-Type-Options", "nosniff"),
+    ];
+    if is_active_content(&content_type) {
+        headers.push(Header::new("Content-Disposition", "attachment"));
+    }
+
+    if not_modified(pre, &etag, &modified) {
+        return Ok(FileContent {
+            status: Status::NotModified,
+            content_type,
+            headers,
+            body: None,
+            from_start: false,
+        });
+    }
+
+    match parse_range(pre, size, &etag, &modified) {
+        ByteRange::Full => Ok(FileContent {
+            status: Status::Ok,
+            content_type,
+            headers,
+            body: Some(FileWindow {
+                file,
+                remaining: size,
+            }),
+            from_start: !pre.head,
+        }),
+        ByteRange::Partial(start, end) => {
+            file.seek(SeekFrom::Start(start)).await.map_err(|e| {
+                ApiResponse::fail(Status::InternalServerError, "file error", Some(&e))
+            })?;
+            headers.push(Header::new(
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, end, size),
+            ));
+            Ok(FileContent {
+                status: Status::PartialContent,
+                content_type,
+                headers,
+                body: Some(FileWindow {
+                    file,
+                    remaining: end - start + 1,
+                }),
+                from_start: start == 0 && !pre.head,
+            })
+        }
+        ByteRange::Unsatisfiable => {
+            headers.push(Header::new("Content-Range", format!("bytes */{}", size)));
+            Ok(FileContent {
+                status: Status::RangeNotSatisfiable,
+                content_type,
+                headers,
+                body: None,
+                from_start: false,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const ETAG: &str = "\"10-1\"";
+
+    fn modified() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap()
+    }
+
+    fn range(value: &str) -> Preconditions {
+        Preconditions {
+            range: Some(value.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        let parse = |value: &str| parse_range(&range(value), 100, ETAG, &modified());
+        assert_eq!(parse("bytes=0-9"), ByteRange::Partial(0, 9));
+        assert_eq!(parse("bytes=90-"), ByteRange::Partial(90, 99));
+        assert_eq!(parse("bytes=-10"), ByteRange::Partial(90, 99));
+        assert_eq!(parse("bytes=-1000"), ByteRange::Partial(0, 99));
+        assert_eq!(parse("bytes=50-1000"), ByteRange::Partial(50, 99));
+        assert_eq!(parse("bytes=100-"), ByteRange::Unsatisfiable);
+        assert_eq!(parse("bytes=-0"), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_invalid_and_multipart_ranges() {
+        let parse = |value: &str| parse_range(&range(value), 100, ETAG, &modified());
+        assert_eq!(parse("bytes=9-0"), ByteRange::Full);
+        assert_eq!(parse("bytes=0-9,20-29"), ByteRange::Full);
+        assert_eq!(parse("items=0-9"), ByteRange::Full);
+        assert_eq!(parse("bytes=a-b"), ByteRange::Full);
+        assert_eq!(parse("bytes=-"), ByteRange::Full);
+        assert_eq!(
+            parse_range(&Preconditions::default(), 100, ETAG, &modified()),
+            ByteRange::Full
+        );
+    }
+
+    #[test]
+    fn empty_file_has_no_satisfiable_range() {
+        assert_eq!(
+            parse_range(&range("bytes=0-"), 0, ETAG, &modified()),
+            ByteRange::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn if_range_must_match_the_current_version() {
+        let mut pre = range("bytes=0-9");
+        pre.if_range = Some(ETAG.to_string());
+        assert_eq!(
+            parse_range(&pre, 100, ETAG, &modified()),
+            ByteRange::Partial(0, 9)
+        );
+        pre.if_range = Some(http_date(&modified()));
+        assert_eq!(
+            parse_range(&pre, 100, ETAG, &modified()),
+            ByteRange::Partial(0, 9)
+        );
+
+        pre.if_range = Some("\"other\"".to_string());
+        assert_eq!(parse_range(&pre, 100, ETAG, &modified()), ByteRange::Full);
+        pre.if_range = Some(http_date(&(modified() - chrono::Duration::seconds(1))));
+        assert_eq!(parse_range(&pre, 100, ETAG, &modified()), ByteRange::Full);
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        let mut pre = Preconditions {
+            if_none_match: Some(format!("\"other\", W/{}", ETAG)),
+            ..Default::default()
+        };
+        assert!(not_modified(&pre, ETAG, &modified()));
+        pre.if_none_match = Some("*".to_string());
+        assert!(not_modified(&pre, ETAG, &modified()));
+
+        pre.if_none_match = Some("\"other\"".to_string());
+        pre.if_modified_since = Some(http_date(&modified()));
+        assert!(!not_modified(&pre, ETAG, &modified()));
+    }
+
+    #[test]
+    fn if_modified_since_compares_whole_seconds() {
+        let since = |date: DateTime<Utc>| Preconditions {
+            if_modified_since: Some(http_date(&date)),
+            ..Default::default()
+        };
+        let modified = modified() + chrono::Duration::milliseconds(500);
+        assert!(not_modified(&since(modified), ETAG, &modified));
+        assert!(!not_modified(
+            &since(modified - chrono::Duration::seconds(1)),
+            ETAG,
+            &modified
+        ));
+        assert!(!not_modified(&Preconditions::default(), ETAG, &modified));
+    }
+
+    #[test]
+    fn pages_and_scripts_are_active_content() {
+        for extension in ["html", "htm", "svg", "xml", "js"] {
+            let content_type = ContentType::from_extension(extension).unwrap();
+            assert!(is_active_content(&content_type), "{}", extension);
+        }
+        assert!(is_active_content(&ContentType::new(
+            "application",
+            "xhtml+xml"
+        )));
+        for extension in ["png", "jpg", "pdf", "txt", "json", "css", "mp4"] {
+            let content_type = ContentType::from_extension(extension).unwrap();
+            assert!(!is_active_content(&content_type), "{}", extension);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn active_content_is_sent_as_a_download() {
+        let dir = std::env::temp_dir().join(format!("serve-test-active-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let header = |file: &FileContent, name: &str| {
+            file.headers
+                .iter()
+                .find(|header| header.name() == name)
+                .map(|header| header.value().to_string())
+        };
+
+        for (name, attachment) in [
+            ("page.html", true),
+            ("image.svg", true),
+            ("image.png", false),
+        ] {
+            let path = dir.join(name);
+            std::fs::write(&path, b"<svg onload=alert(1)>").unwrap();
+            let file = serve_file(&path, modified(), &Preconditions::default())
+                .await
+                .unwrap();
+            assert_eq!(
+                header(&file, "X-Content-Type-Options").as_deref(),
+                Some("nosniff")
+            );
+            assert_eq!(
+                header(&file, "Content-Disposition").as_deref(),
+                attachment.then_some("attachment"),
+                "{}",
+                name
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn only_data_from_the_first_byte_counts_as_a_download() {
+        let path = std::env::temp_dir().join(format!("serve-test-{}.txt", std::process::id()));
+        std::fs::write(&path, [0u8; 100]).unwrap();
+        let serve = |pre: Preconditions| {
+            let path = path.clone();
+            async move { serve_file(&path, modified(), &pre).await.unwrap() }
+        };
+
+        let full = serve(Preconditions::default()).await;
+        assert_eq!(full.status, Status::Ok);
+        assert!(full.counts_as_download());
+        assert!(serve(range("bytes=0-")).await.counts_as_download());
+        assert!(serve(range("bytes=-100")).await.counts_as_download());
+
+        let partial = serve(range("bytes=10-")).await;
+        assert_eq!(partial.status, Status::PartialContent);
+        assert!(!partial.counts_as_download());
+        let unsatisfiable = serve(range("bytes=100-")).await;
+        assert_eq!(unsatisfiable.status, Status::RangeNotSatisfiable);
+        assert!(!unsatisfiable.counts_as_download());
+        let head = serve(Preconditions {
+            head: true,
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(head.status, Status::Ok);
+        assert!(!head.counts_as_download());
+        let cached = serve(Preconditions {
+            if_modified_since: Some(http_date(&modified())),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(cached.status, Status::NotModified);
+        assert!(!cached.counts_as_download());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+use crate::auth::AuthAdminUser;
+use crate::models::{ApiResponse, Settings};
+use crate::ApiResult;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+#[get("/settings")]
+pub async fn get_settings(pool: &State<PgPool>, admin: AuthAdminUser) -> ApiResult<Json<Settings>> {
+    let _admin = admin?;
+    let settings = sqlx::query_as!(
+        Settings,
+        "SELECT require_admin_2fa, updated_at FROM settings"
+    )
+    .fetch_one(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(settings))
+}
+
+#[derive(Deserialize)]
+pub struct EditSettingsData {
+    pub require_admin_2fa: Option<bool>,
+}
+
+#[patch("/settings", format = "json", data = "<data>")]
+pub async fn edit_settings(
+    data: Json<EditSettingsData>,
+    pool: &State<PgPool>,
+    admin: AuthAdminUser,
+) -> ApiResult<Json<Settings>> {
+    let admin = admin?;
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    // otherwise the admin turning it on would lose access to this endpoint right away
+    if data.require_admin_2fa == Some(true) {
+        let enabled = sqlx::query_scalar!(
+            "SELECT totp_enabled FROM users WHERE id = $1",
+            admin.user_id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+        if !enabled {
+            return Err(ApiResponse::fail(
+                Status::BadRequest,
+                "enable two-factor authentication on your account first",
+                None,
+            ));
+        }
+    }
+
+    let settings = sqlx::query_as!(
+        Settings,
+        r#"
+        UPDATE settings SET require_admin_2fa = COALESCE($1, require_admin_2fa)
+        RETURNING require_admin_2fa, updated_at
+        "#,
+        data.require_admin_2fa
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(settings))
+}
@@ -0,0 +1,166 @@
+use crate::auth::endpoints::{insert_user, login_cookie, validate_new_user, UserAgentIp};
+use crate::auth::tokens::hash_token;
+use crate::models::{ApiResponse, User};
+use crate::ApiResult;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::http::{CookieJar, Status};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Mutex;
+
+// hash of the one-time token for creating the first admin, `None` once there is one
+pub struct Setup(Mutex<Option<String>>);
+
+async fn setup_needed(pool: &PgPool) -> bool {
+    sqlx::query_scalar!(
+        r#"SELECT NOT setup_completed AND NOT EXISTS(SELECT 1 FROM users) AS "needed!" FROM settings"#
+    )
+    .fetch_one(pool)
+    .await
+    .expect("failed to check if the setup is needed")
+}
+
+// creates the admin and closes the setup in one transaction, so only the first request can do it
+async fn create_first_admin(pool: &PgPool, login: &str, password: &str) -> ApiResult<Option<User>> {
+    let login = validate_new_user(login, password)?;
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let claimed = sqlx::query!(
+        "UPDATE settings SET setup_completed = TRUE WHERE NOT setup_completed AND NOT EXISTS(SELECT 1 FROM users)"
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    if claimed.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    let user = insert_user(&mut tx, login, password, true).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    Ok(Some(user))
+}
+
+impl Setup {
+    // INITIAL_ADMIN_LOGIN and INITIAL_ADMIN_PASSWORD create the admin right away,
+    // without them a token is printed and the admin is created with `POST /setup`
+    pub async fn init(pool: &PgPool) -> Self {
+        if !setup_needed(pool).await {
+            return Self(Mutex::new(None));
+        }
+
+        if let (Ok(login), Ok(password)) = (
+            std::env::var("INITIAL_ADMIN_LOGIN"),
+            std::env::var("INITIAL_ADMIN_PASSWORD"),
+        ) {
+            match create_first_admin(pool, &login, &password).await {
+                Ok(Some(user)) => {
+                    log::warn!(
+                        "created the first admin {} from the environment",
+                        user.login
+                    );
+                    return Self(Mutex::new(None));
+                }
+                Ok(None) => return Self(Mutex::new(None)),
+                Err(e) => log::error!(
+                    "INITIAL_ADMIN_LOGIN and INITIAL_ADMIN_PASSWORD can't be used: {}",
+                    e.1.detail.clone().unwrap_or_default()
+                ),
+            }
+        }
+
+        let mut bytes = [0u8; 24];
+        OsRng.fill_bytes(&mut bytes);
+        let token = bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        log::warn!(
+            "there are no users yet, create the first admin at {}/setup with the setup token {}",
+            std::env::var("ALLOWED_ORIGIN").unwrap_or_default(),
+            token
+        );
+        Self(Mutex::new(Some(hash_token(&token))))
+    }
+
+    fn is_open(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    fn check_token(&self, token: &str) -> bool {
+        self.0.lock().unwrap().as_deref() == Some(hash_token(token.trim()).as_str())
+    }
+
+    fn close(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+#[derive(Serialize)]
+pub struct SetupStatus {
+    pub required: bool,
+}
+
+#[get("/setup")]
+pub async fn get_setup(setup: &State<Setup>) -> Json<SetupStatus> {
+    Json(SetupStatus {
+        required: setup.is_open(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct SetupData {
+    pub token: String,
+    pub login: String,
+    pub password: String,
+}
+
+// creates the first admin and logs them in
+#[post("/setup", format = "json", data = "<data>")]
+pub async fn finish_setup(
+    data: Json<SetupData>,
+    uaip: UserAgentIp,
+    setup: &State<Setup>,
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>,
+) -> ApiResult {
+    if !setup.is_open() {
+        return Err(ApiResponse::fail(
+            Status::Gone,
+            "the setup is already finished",
+            None,
+        ));
+    }
+    if !setup.check_token(&data.token) {
+        return Err(ApiResponse::fail(
+            Status::Forbidden,
+            "wrong setup token",
+            None,
+        ));
+    }
+
+    let Some(user) = create_first_admin(pool.inner(), &data.login, &data.password).await? else {
+        setup.close();
+        return Err(ApiResponse::fail(
+            Status::Gone,
+            "the setup is already finished",
+            None,
+        ));
+    };
+    setup.close();
+    log::warn!(
+        "created the first admin {} with the setup token",
+        user.login
+    );
+
+    login_cookie(uaip, pool, cookies, user, false).await?;
+    Ok((Status::Ok, ApiResponse::success()))
+}
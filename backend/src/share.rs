@@ -0,0 +1,555 @@
+use crate::auth::endpoints::UserAgentIp;
+use crate::auth::lockout::{check_lockout, clear_login_failures, record_login_failure};
+use crate::auth::{create_jwt_cookie, AuthUser, JWTData};
+use crate::models::{ApiResponse, File, Folder, ShareLink, Visibility};
+use crate::perms::{check_permission, PermissionKind};
+use crate::{ApiResult, SHARE_UNLOCK_TIME};
+use bcrypt::{hash, verify, DEFAULT_COST};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rocket::http::private::cookie::Expiration;
+use rocket::http::{CookieJar, Status};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+// share tokens don't expire by themselves, expiry and revocation are checked against the database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ShareClaims {
+    link_id: Uuid,
+    item_id: Uuid,
+}
+
+fn sign_share(link: &ShareLink) -> ApiResult<String> {
+    let jwt_secret = std::env::var("JWT_SECRET").map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })?;
+    let claims = ShareClaims {
+        link_id: link.id,
+        item_id: link.file_id.or(link.folder_id).unwrap_or_default(),
+    };
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(jwt_secret.as_bytes()),
+    )
+    .map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })
+}
+
+fn verify_share(token: &str) -> ApiResult<ShareClaims> {
+    let jwt_secret = std::env::var("JWT_SECRET").map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })?;
+    let mut validation = Validation::default();
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+    decode::<ShareClaims>(
+        token,
+        &DecodingKey::from_secret(jwt_secret.as_bytes()),
+        &validation,
+    )
+    .map(|v| v.claims)
+    .map_err(|_| ApiResponse::fail(Status::NotFound, "share link not found", None))
+}
+
+// contents of the cookie that `unlock_share` hands out for a link with a password
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ShareUnlockData {
+    link_id: Uuid,
+}
+
+fn unlock_cookie_name(link_id: Uuid) -> String {
+    format!("share_{}", link_id.simple())
+}
+
+// wrong passwords are limited like logins, the link takes the place of the account
+fn lockout_subject(link_id: Uuid) -> String {
+    format!("share:{}", link_id)
+}
+
+fn is_unlocked(cookies: &CookieJar<'_>, link_id: Uuid) -> ApiResult<bool> {
+    let Some(cookie) = cookies.get_private(&unlock_cookie_name(link_id)) else {
+        return Ok(false);
+    };
+    let jwt_secret = std::env::var("JWT_SECRET").map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })?;
+    Ok(decode::<JWTData<ShareUnlockData>>(
+        cookie.value(),
+        &DecodingKey::from_secret(jwt_secret.as_bytes()),
+        &Validation::default(),
+    )
+    .is_ok_and(|token| token.claims.data.link_id == link_id))
+}
+
+// the link without looking at its password
+async fn find_share(tx: &mut PgConnection, token: &str) -> ApiResult<ShareLink> {
+    let claims = verify_share(token)?;
+    let link = sqlx::query_as!(
+        ShareLink,
+        "SELECT * FROM share_links WHERE id = $1",
+        claims.link_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?
+    .filter(|link| link.file_id.or(link.folder_id) == Some(claims.item_id))
+    .ok_or_else(|| ApiResponse::fail(Status::NotFound, "share link not found", None))?;
+
+    if link.expires_at.is_some_and(|exp| exp < Utc::now()) {
+        return Err(ApiResponse::fail(
+            Status::Gone,
+            "share link has expired",
+            None,
+        ));
+    }
+    if link.max_downloads.is_some_and(|max| link.downloads >= max) {
+        return Err(ApiResponse::fail(
+            Status::Gone,
+            "share link download limit reached",
+            None,
+        ));
+    }
+    Ok(link)
+}
+
+async fn load_share(
+    tx: &mut PgConnection,
+    token: &str,
+    cookies: &CookieJar<'_>,
+) -> ApiResult<ShareLink> {
+    let link = find_share(tx, token).await?;
+    if link.password.is_some() && !is_unlocked(cookies, link.id)? {
+        return Err(ApiResponse::fail(
+            Status::Unauthorized,
+            "share link requires a password",
+            None,
+        ));
+    }
+    Ok(link)
+}
+
+async fn is_within_share(
+    tx: &mut PgConnection,
+    link: &ShareLink,
+    folder_id: Option<Uuid>,
+) -> ApiResult<bool> {
+    let (Some(shared), Some(folder_id)) = (link.folder_id, folder_id) else {
+        return Ok(false);
+    };
+    Ok(
+        sqlx::query_scalar!("SELECT folder_is_within($1, $2)", folder_id, shared)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| {
+                ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+            })?
+            .unwrap_or(false),
+    )
+}
+
+// lets a share link stand in for `check_permission` when downloading a file, returns the id of
+// the link for `count_share_download`
+pub async fn use_share_link(
+    tx: &mut PgConnection,
+    token: &str,
+    cookies: &CookieJar<'_>,
+    file_id: Uuid,
+    folder_id: Option<Uuid>,
+) -> ApiResult<Uuid> {
+    let link = load_share(tx, token, cookies).await?;
+
+    if link.file_id != Some(file_id) && !is_within_share(tx, &link, folder_id).await? {
+        return Err(ApiResponse::fail(
+            Status::Forbidden,
+            "file is not part of this share link",
+            None,
+        ));
+    }
+    Ok(link.id)
+}
+
+// called once it's known what is actually sent, see `FileContent::counts_as_download`
+pub async fn count_share_download(tx: &mut PgConnection, link_id: Uuid) -> ApiResult<()> {
+    let res = sqlx::query!(
+        "UPDATE share_links SET downloads = downloads + 1 WHERE id = $1 AND (max_downloads IS NULL OR downloads < max_downloads)",
+        link_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    if res.rows_affected() == 0 {
+        return Err(ApiResponse::fail(
+            Status::Gone,
+            "share link download limit reached",
+            None,
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct ShareLinkData {
+    pub id: Uuid,
+    pub token: String,
+    pub owner_id: Uuid,
+    pub file_id: Option<Uuid>,
+    pub folder_id: Option<Uuid>,
+    pub has_password: bool,
+    pub max_downloads: Option<i32>,
+    pub downloads: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ShareLinkData {
+    fn new(link: ShareLink) -> ApiResult<Self> {
+        Ok(Self {
+            token: sign_share(&link)?,
+            id: link.id,
+            owner_id: link.owner_id,
+            file_id: link.file_id,
+            folder_id: link.folder_id,
+            has_password: link.password.is_some(),
+            max_downloads: link.max_downloads,
+            downloads: link.downloads,
+            expires_at: link.expires_at,
+            created_at: link.created_at,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewShareData {
+    pub file: Option<Uuid>,
+    pub folder: Option<Uuid>,
+    pub password: Option<String>,
+    pub max_downloads: Option<i32>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[post("/share", format = "json", data = "<data>")]
+pub async fn create_share(
+    data: Json<NewShareData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<ShareLinkData>> {
+    let auth = auth?;
+
+    if data.file.is_some() == data.folder.is_some() {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "share link must point to either a file or a folder",
+            None,
+        ));
+    }
+    if data.expires_at.is_some_and(|exp| exp < Utc::now()) {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "expiration date must be in the future",
+            None,
+        ));
+    }
+    if data.max_downloads.is_some_and(|max| max < 1) {
+        return Err(ApiResponse::fail(
+            Status::BadRequest,
+            "download limit must be at least 1",
+            None,
+        ));
+    }
+    let password = match &data.password {
+        Some(password) if password.is_empty() => {
+            return Err(ApiResponse::fail(
+                Status::BadRequest,
+                "password cannot be empty",
+                None,
+            ));
+        }
+        Some(password) => Some(hash(password, DEFAULT_COST).map_err(|e| {
+            ApiResponse::fail(
+                Status::InternalServerError,
+                "internal server error",
+                Some(&e),
+            )
+        })?),
+        None => None,
+    };
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let folder_id = match data.file {
+        Some(file) => sqlx::query_scalar!("SELECT folder_id FROM files WHERE id = $1", file)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| {
+                ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+            })?
+            .ok_or_else(|| ApiResponse::fail(Status::NotFound, "file not found", None))?,
+        None => data.folder,
+    };
+    check_permission(&mut tx, &auth, folder_id, PermissionKind::Share).await?;
+
+    let link = sqlx::query_as!(
+        ShareLink,
+        "INSERT INTO share_links (owner_id, file_id, folder_id, password, max_downloads, expires_at) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+        auth.user_id,
+        data.file,
+        data.folder,
+        password,
+        data.max_downloads,
+        data.expires_at,
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+        if let sqlx::Error::Database(db_err) = &e
+            && db_err.is_foreign_key_violation()
+        {
+            ApiResponse::fail(Status::NotFound, "folder not found", None)
+        } else {
+            ApiResponse::fail(Status::InternalServerError, "database error", Some(&e))
+        }
+    })?;
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(ShareLinkData::new(link)?))
+}
+
+#[get("/shares?<file>&<folder>")]
+pub async fn get_shares(
+    file: Option<Uuid>,
+    folder: Option<Uuid>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult<Json<Vec<ShareLinkData>>> {
+    let auth = auth?;
+    auth.require_full_access()?;
+
+    let links = sqlx::query_as!(
+        ShareLink,
+        r#"
+        SELECT * FROM share_links
+        WHERE (owner_id = $1 OR $2)
+          AND ($3::uuid IS NULL OR file_id = $3)
+          AND ($4::uuid IS NULL OR folder_id = $4)
+        ORDER BY created_at DESC
+        "#,
+        auth.user_id,
+        auth.admin,
+        file,
+        folder
+    )
+    .fetch_all(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(
+        links
+            .into_iter()
+            .map(ShareLinkData::new)
+            .collect::<ApiResult<_>>()?,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct RemoveShareData {
+    pub id: Uuid,
+}
+
+#[delete("/share", format = "json", data = "<data>")]
+pub async fn delete_share(
+    data: Json<RemoveShareData>,
+    pool: &State<PgPool>,
+    auth: AuthUser,
+) -> ApiResult {
+    let auth = auth?;
+    auth.require_full_access()?;
+
+    let res = sqlx::query!(
+        "DELETE FROM share_links WHERE id = $1 AND (owner_id = $2 OR $3)",
+        data.id,
+        auth.user_id,
+        auth.admin
+    )
+    .execute(pool.inner())
+    .await
+    .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    if res.rows_affected() == 0 {
+        return Err(ApiResponse::fail(
+            Status::NotFound,
+            "share link not found",
+            None,
+        ));
+    }
+
+    Ok((
+        Status::NoContent,
+        ApiResponse::success_with("revoked share link"),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct UnlockShareData {
+    pub password: String,
+}
+
+// exchanges the password for a cookie, so it doesn't end up in urls and server logs
+#[post("/share/<token>/unlock", format = "json", data = "<data>")]
+pub async fn unlock_share(
+    token: &str,
+    data: Json<UnlockShareData>,
+    uaip: UserAgentIp,
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>,
+) -> ApiResult {
+    let subject = lockout_subject(verify_share(token)?.link_id);
+    check_lockout(pool.inner(), &subject, uaip.client_ip).await?;
+
+    let mut conn = pool
+        .acquire()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+    let link = find_share(&mut conn, token).await?;
+    let Some(hashed) = &link.password else {
+        return Ok((Status::Ok, ApiResponse::success()));
+    };
+    if !verify(&data.password, hashed).map_err(|e| {
+        ApiResponse::fail(
+            Status::InternalServerError,
+            "internal server error",
+            Some(&e),
+        )
+    })? {
+        record_login_failure(pool.inner(), &subject, uaip.client_ip).await?;
+        return Err(ApiResponse::fail(
+            Status::Forbidden,
+            "wrong share link password",
+            None,
+        ));
+    }
+
+    clear_login_failures(pool.inner(), &subject).await?;
+    let unlock = JWTData {
+        data: ShareUnlockData { link_id: link.id },
+        exp: (Utc::now() + SHARE_UNLOCK_TIME).timestamp() as usize,
+    };
+    create_jwt_cookie(
+        cookies,
+        unlock_cookie_name(link.id),
+        &unlock,
+        Expiration::Session,
+    )?;
+    Ok((Status::Ok, ApiResponse::success()))
+}
+
+#[derive(Serialize)]
+pub struct ShareContents {
+    pub expires_at: Option<DateTime<Utc>>,
+    pub folder: Option<Folder>,
+    pub folders: Vec<Folder>,
+    pub files: Vec<File>,
+}
+
+// what an outsider sees when opening a share link, for folders `folder` lets them browse subfolders
+#[get("/share/<token>?<folder>")]
+pub async fn get_share(
+    token: &str,
+    folder: Option<Uuid>,
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>,
+) -> ApiResult<Json<ShareContents>> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    let link = load_share(&mut tx, token, cookies).await?;
+
+    let contents = if let Some(file_id) = link.file_id {
+        let file = sqlx::query_as!(
+            File,
+            "SELECT id, folder_id, owner_id, name, size, created_at, updated_at FROM files WHERE id = $1",
+            file_id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+        ShareContents {
+            expires_at: link.expires_at,
+            folder: None,
+            folders: vec![],
+            files: vec![file],
+        }
+    } else {
+        let folder_id = folder.or(link.folder_id);
+        if !is_within_share(&mut tx, &link, folder_id).await? {
+            return Err(ApiResponse::fail(
+                Status::Forbidden,
+                "folder is not part of this share link",
+                None,
+            ));
+        }
+        let folder = sqlx::query_as!(
+            Folder,
+            r#"SELECT id, parent_id, name, owner_id, visibility AS "visibility: Visibility", created_at, updated_at FROM folders WHERE id = $1"#,
+            folder_id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+        let folders = sqlx::query_as!(
+            Folder,
+            r#"SELECT id, parent_id, name, owner_id, visibility AS "visibility: Visibility", created_at, updated_at FROM folders WHERE parent_id = $1 ORDER BY LOWER(name), name"#,
+            folder_id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+        let files = sqlx::query_as!(
+            File,
+            "SELECT id, folder_id, owner_id, name, size, created_at, updated_at FROM files WHERE folder_id = $1 ORDER BY LOWER(name), name",
+            folder_id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+        ShareContents {
+            expires_at: link.expires_at,
+            folder: Some(folder),
+            folders,
+            files,
+        }
+    };
+
+    tx.commit()
+        .await
+        .map_err(|e| ApiResponse::fail(Status::InternalServerError, "database error", Some(&e)))?;
+
+    Ok(Json(contents))
+}
@@ -0,0 +1,94 @@
+// shared by the tests that run the whole application against a fresh database from `#[sqlx::test]`
+#![allow(dead_code)]
+
+use chrono::{Duration, Utc};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use lempek_assets_backend::auth::endpoints::insert_user;
+use lempek_assets_backend::auth::{JWTData, RefreshTokenData};
+use lempek_assets_backend::build;
+use lempek_assets_backend::models::User;
+use rocket::http::{ContentType, Cookie, Status};
+use rocket::local::asynchronous::Client;
+use rocket::serde::json::json;
+use sqlx::PgPool;
+use std::sync::Once;
+use uuid::Uuid;
+
+pub const ORIGIN: &str = "https://assets.example.com";
+pub const PASSWORD: &str = "password1";
+const JWT_SECRET: &str = "test secret";
+
+// the server reads these on every request, they are set once before any test starts a client
+fn init_env() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
+        std::env::set_var("JWT_SECRET", JWT_SECRET);
+        std::env::set_var("ALLOWED_ORIGIN", ORIGIN);
+    });
+}
+
+pub async fn client(pool: &PgPool) -> Client {
+    init_env();
+    Client::tracked(build(pool.clone()).await)
+        .await
+        .expect("valid rocket instance")
+}
+
+pub async fn create_user(pool: &PgPool, login: &str) -> User {
+    insert_account(pool, login, false).await
+}
+
+pub async fn create_admin(pool: &PgPool, login: &str) -> User {
+    insert_account(pool, login, true).await
+}
+
+async fn insert_account(pool: &PgPool, login: &str, admin: bool) -> User {
+    let mut conn = pool.acquire().await.unwrap();
+    insert_user(&mut conn, login, PASSWORD, admin)
+        .await
+        .unwrap_or_else(|(status, _)| panic!("creating {} failed with {}", login, status))
+}
+
+pub async fn login(client: &Client, login: &str) {
+    let response = client
+        .post("/api/login")
+        .header(ContentType::JSON)
+        .body(json!({ "login": login, "password": PASSWORD }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+// accounts from single sign-on or the directory can't log in with a password here,
+// the client gets the refresh cookie of a new session instead
+pub async fn login_without_password(client: &Client, pool: &PgPool, user: &User) {
+    let refresh_token: Uuid = sqlx::query_scalar(
+        "INSERT INTO user_tokens (user_id, expires_at, stay_logged_in) VALUES ($1, $2, FALSE) RETURNING refresh_token",
+    )
+    .bind(user.id)
+    .bind(Utc::now() + Duration::hours(1))
+    .fetch_one(pool)
+    .await
+    .unwrap();
+    let refresh_data = JWTData {
+        data: RefreshTokenData {
+            user_id: user.id,
+            refresh_token,
+            stay_logged_in: false,
+        },
+        exp: (Utc::now() + Duration::hours(1)).timestamp() as usize,
+    };
+    let token = encode(
+        &Header::default(),
+        &refresh_data,
+        &EncodingKey::from_secret(JWT_SECRET.as_bytes()),
+    )
+    .unwrap();
+
+    let response = client
+        .get("/api/user")
+        .private_cookie(Cookie::new("refresh_token", token))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
@@ -0,0 +1,39 @@
+// choosing who can see a new folder needs the right to manage permissions, not just to upload
+mod common;
+
+use common::{client, create_user, login};
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::{json, serde_json};
+use sqlx::PgPool;
+
+#[sqlx::test]
+async fn uploader_cant_create_public_folder(pool: PgPool) {
+    let alice = create_user(&pool, "alice").await;
+    sqlx::query(
+        "UPDATE permissions SET list = TRUE, read = TRUE, upload = TRUE WHERE user_id = $1",
+    )
+    .bind(alice.id)
+    .execute(&pool)
+    .await
+    .unwrap();
+    let client = client(&pool).await;
+    login(&client, "alice").await;
+
+    for visibility in ["public", "private"] {
+        let response = client
+            .post("/api/folder")
+            .header(ContentType::JSON)
+            .body(json!({ "name": "published", "visibility": visibility }).to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["detail"], "no permissions to manage_permissions");
+    }
+
+    let folders = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM folders")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(folders, 0);
+}
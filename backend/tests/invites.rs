@@ -0,0 +1,133 @@
+// invite links create at most `max_uses` accounts, a failed redemption doesn't use one up
+mod common;
+
+use chrono::{Duration, Utc};
+use common::{client, create_admin, create_user, login};
+use rocket::http::{ContentType, Status};
+use rocket::local::asynchronous::Client;
+use rocket::serde::json::{json, serde_json};
+use sqlx::PgPool;
+
+async fn create_invite(pool: &PgPool, max_uses: i32) -> String {
+    create_admin(pool, "admin").await;
+    let admin = client(pool).await;
+    login(&admin, "admin").await;
+    let response = admin
+        .post("/api/invite")
+        .header(ContentType::JSON)
+        .body(
+            json!({ "expires_at": Utc::now() + Duration::days(1), "max_uses": max_uses })
+                .to_string(),
+        )
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let invite: serde_json::Value = response.into_json().await.unwrap();
+    invite["token"].as_str().unwrap().to_string()
+}
+
+async fn redeem(pool: &PgPool, token: &str, login: &str) -> Status {
+    let client = client(pool).await;
+    client
+        .post("/api/invite/redeem")
+        .header(ContentType::JSON)
+        .body(json!({ "token": token, "login": login, "password": "password1" }).to_string())
+        .dispatch()
+        .await
+        .status()
+}
+
+async fn is_valid(client: &Client, token: &str) -> bool {
+    let response = client
+        .get(format!("/api/invite?token={}", token))
+        .dispatch()
+        .await;
+    let status: serde_json::Value = response.into_json().await.unwrap();
+    status["valid"].as_bool().unwrap()
+}
+
+#[sqlx::test]
+async fn invite_is_used_up_after_max_uses(pool: PgPool) {
+    let token = create_invite(&pool, 2).await;
+    let anonymous = client(&pool).await;
+
+    assert_eq!(redeem(&pool, &token, "alice").await, Status::Ok);
+    assert!(is_valid(&anonymous, &token).await);
+    assert_eq!(redeem(&pool, &token, "bob").await, Status::Ok);
+    assert!(!is_valid(&anonymous, &token).await);
+    assert_eq!(redeem(&pool, &token, "carol").await, Status::Gone);
+
+    let carol = sqlx::query_scalar!("SELECT COUNT(*) FROM users WHERE login = 'carol'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(carol, Some(0));
+}
+
+#[sqlx::test]
+async fn failed_redemption_gives_the_use_back(pool: PgPool) {
+    let token = create_invite(&pool, 1).await;
+    create_user(&pool, "alice").await;
+
+    assert_eq!(redeem(&pool, &token, "alice").await, Status::Conflict);
+    assert_eq!(redeem(&pool, &token, "bob").await, Status::Ok);
+    assert_eq!(redeem(&pool, &token, "carol").await, Status::Gone);
+}
+
+#[sqlx::test]
+async fn redeemed_account_is_logged_in(pool: PgPool) {
+    let token = create_invite(&pool, 1).await;
+    let client = client(&pool).await;
+    let response = client
+        .post("/api/invite/redeem")
+        .header(ContentType::JSON)
+        .body(
+            json!({ "token": token, "login": "alice", "username": "Alice", "password": "password1" })
+                .to_string(),
+        )
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let me: serde_json::Value = client
+        .get("/api/user")
+        .dispatch()
+        .await
+        .into_json()
+        .await
+        .unwrap();
+    assert_eq!(me["username"], "Alice");
+}
+
+#[sqlx::test]
+async fn taken_username_is_a_conflict(pool: PgPool) {
+    let token = create_invite(&pool, 1).await;
+    create_user(&pool, "alice").await;
+    let client = client(&pool).await;
+    let response = client
+        .post("/api/invite/redeem")
+        .header(ContentType::JSON)
+        .body(
+            json!({ "token": token, "login": "bob", "username": "alice", "password": "password1" })
+                .to_string(),
+        )
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Conflict);
+    let body: serde_json::Value = response.into_json().await.unwrap();
+    assert_eq!(body["detail"], "username is already taken");
+
+    let response = client
+        .post("/api/invite/redeem")
+        .header(ContentType::JSON)
+        .body(
+            json!({ "token": token, "login": "bob", "username": "bob\u{0}", "password": "password1" })
+                .to_string(),
+        )
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadRequest);
+
+    // nothing was created and the invite can still be used
+    assert_eq!(redeem(&pool, &token, "bob").await, Status::Ok);
+}
@@ -0,0 +1,354 @@
+// the whole single sign-on round trip against a small provider running next to the tests
+mod common;
+
+use chrono::{Duration, Utc};
+use common::{client, create_user, login, ORIGIN};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use openidconnect::url::{form_urlencoded, Url};
+use rocket::http::Status;
+use rocket::local::asynchronous::Client;
+use rocket::serde::json::{json, Value};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Mutex, OnceLock};
+use uuid::Uuid;
+
+const CLIENT_ID: &str = "assets";
+const KEY_ID: &str = "test";
+const KEY: &str = include_str!("fixtures/oidc_key.pem");
+// modulus of the key above for the provider's key set
+const KEY_MODULUS: &str = "qLvGUUK5ytRBvKVtdnCNdmrDhlfsR7WjZpuDo5UOVzb7iDZ1FvPzSkMgXPmBEWwuCefNrC7dhFEFtGxbmlfqldSReFhJ_mwWOy7fAVJo-1fBb4yhT3v3OAYivgLckt9ySLqZowK2WlEdkAbu74OwkX0OSPixHeQqB1MK2KBlcO-S57qpRGRPuqB_heftJQ8-1cjk1sJbTzpRCnRoNDPFYmA0PLKyzP8FW-uoJnESoWn33c4gUMDkRnA_GGa-pCPm0TS4KF0DZG6GPw233bV5T5VdT01qaGZO3_dJliUiRD2FitgKF3zbYdlSnXu-LlrRXQXOQWfQ8Ya0tPoFw_n3tQ";
+
+// answers discovery, the key set and the token endpoint, tests register the id token
+// a code is exchanged for before sending the browser back with it
+struct Provider {
+    issuer: String,
+    id_tokens: Mutex<HashMap<String, String>>,
+}
+
+fn provider() -> &'static Provider {
+    static PROVIDER: OnceLock<&'static Provider> = OnceLock::new();
+    PROVIDER.get_or_init(|| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let provider: &'static Provider = Box::leak(Box::new(Provider {
+            issuer: issuer.clone(),
+            id_tokens: Mutex::new(HashMap::new()),
+        }));
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                provider.answer(stream);
+            }
+        });
+
+        // the server reads the configuration on every request
+        unsafe {
+            std::env::set_var("OIDC_ISSUER_URL", &issuer);
+            std::env::set_var("OIDC_CLIENT_ID", CLIENT_ID);
+            std::env::set_var(
+                "OIDC_REDIRECT_URL",
+                format!("{}/api/login/oidc/callback", ORIGIN),
+            );
+            std::env::set_var("OIDC_AUTO_PROVISION", "true");
+            std::env::set_var("OIDC_ADMIN_CLAIM", "groups");
+            std::env::set_var("OIDC_ADMIN_VALUE", "admins");
+        }
+        provider
+    })
+}
+
+impl Provider {
+    fn answer(&self, stream: std::net::TcpStream) {
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+
+        let path = request_line.split(' ').nth(1).unwrap_or_default();
+        let (status, response) = match path {
+            "/.well-known/openid-configuration" => (
+                "200 OK",
+                json!({
+                    "issuer": self.issuer,
+                    "authorization_endpoint": format!("{}/authorize", self.issuer),
+                    "token_endpoint": format!("{}/token", self.issuer),
+                    "jwks_uri": format!("{}/jwks", self.issuer),
+                    "response_types_supported": ["code"],
+                    "subject_types_supported": ["public"],
+                    "id_token_signing_alg_values_supported": ["RS256"],
+                }),
+            ),
+            "/jwks" => (
+                "200 OK",
+                json!({ "keys": [{
+                    "kty": "RSA",
+                    "use": "sig",
+                    "alg": "RS256",
+                    "kid": KEY_ID,
+                    "n": KEY_MODULUS,
+                    "e": "AQAB",
+                }] }),
+            ),
+            "/token" => {
+                let id_token = form_urlencoded::parse(&body)
+                    .find(|(name, _)| name == "code")
+                    .and_then(|(_, code)| self.id_tokens.lock().unwrap().remove(code.as_ref()));
+                match id_token {
+                    Some(id_token) => (
+                        "200 OK",
+                        json!({ "access_token": "access", "token_type": "Bearer", "id_token": id_token }),
+                    ),
+                    None => ("400 Bad Request", json!({ "error": "invalid_grant" })),
+                }
+            }
+            _ => ("404 Not Found", json!({})),
+        };
+
+        let response = response.to_string();
+        write!(
+            &stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            response.len(),
+            response
+        )
+        .unwrap();
+    }
+
+    // signs an id token with the given claims on top of the ones every token has
+    fn issue(&self, nonce: &str, claims: Value) -> String {
+        let mut id_token = json!({
+            "iss": self.issuer,
+            "aud": CLIENT_ID,
+            "iat": Utc::now().timestamp(),
+            "exp": (Utc::now() + Duration::minutes(5)).timestamp(),
+            "nonce": nonce,
+        });
+        id_token
+            .as_object_mut()
+            .unwrap()
+            .extend(claims.as_object().unwrap().clone());
+        let mut header = Header::new(jsonwebtoken::Algorithm::RS256);
+        header.kid = Some(KEY_ID.to_string());
+        let id_token = encode(
+            &header,
+            &id_token,
+            &EncodingKey::from_rsa_pem(KEY.as_bytes()).unwrap(),
+        )
+        .unwrap();
+
+        let code = Uuid::now_v7().to_string();
+        self.id_tokens
+            .lock()
+            .unwrap()
+            .insert(code.clone(), id_token);
+        code
+    }
+}
+
+struct Authorization {
+    state: String,
+    nonce: String,
+}
+
+// starts the login and reads what the browser would take to the provider
+async fn start(client: &Client) -> Authorization {
+    let provider = provider();
+    let response = client.get("/api/login/oidc").dispatch().await;
+    assert_eq!(response.status(), Status::SeeOther);
+    let location = Url::parse(response.headers().get_one("Location").unwrap()).unwrap();
+    assert!(location.as_str().starts_with(&provider.issuer));
+    let param = |name: &str| {
+        location
+            .query_pairs()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.into_owned())
+            .unwrap()
+    };
+    Authorization {
+        state: param("state"),
+        nonce: param("nonce"),
+    }
+}
+
+// sends the browser back from the provider, returns the error shown by the frontend if any
+async fn callback(client: &Client, code: &str, state: &str) -> Option<String> {
+    let response = client
+        .get(format!(
+            "/api/login/oidc/callback?code={}&state={}",
+            code, state
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::SeeOther);
+    let location = Url::parse(response.headers().get_one("Location").unwrap()).unwrap();
+    assert!(location.as_str().starts_with(ORIGIN));
+    location
+        .query_pairs()
+        .find(|(param, _)| param == "error")
+        .map(|(_, error)| error.into_owned())
+}
+
+async fn current_user(client: &Client) -> Option<Value> {
+    let response = client.get("/api/user").dispatch().await;
+    if response.status() != Status::Ok {
+        return None;
+    }
+    response.into_json().await
+}
+
+async fn identities(pool: &PgPool) -> Vec<(Uuid, String)> {
+    sqlx::query_as("SELECT user_id, subject FROM user_identities ORDER BY subject")
+        .fetch_all(pool)
+        .await
+        .unwrap()
+}
+
+#[sqlx::test]
+async fn provisions_accounts_and_maps_the_admin_claim(pool: PgPool) {
+    let client = client(&pool).await;
+    let provider = provider();
+
+    let authorization = start(&client).await;
+    let code = provider.issue(
+        &authorization.nonce,
+        json!({ "sub": "jane", "preferred_username": "jane.doe", "groups": ["users", "admins"] }),
+    );
+    assert_eq!(callback(&client, &code, &authorization.state).await, None);
+    let user = current_user(&client).await.unwrap();
+    assert_eq!(user["login"], "janedoe");
+    assert_eq!(user["admin"], true);
+
+    // the provider decides on every login, taking the group away takes the rights away
+    let client = common::client(&pool).await;
+    let authorization = start(&client).await;
+    let code = provider.issue(
+        &authorization.nonce,
+        json!({ "sub": "jane", "preferred_username": "jane.doe", "groups": ["users"] }),
+    );
+    assert_eq!(callback(&client, &code, &authorization.state).await, None);
+    let user = current_user(&client).await.unwrap();
+    assert_eq!(user["login"], "janedoe");
+    assert_eq!(user["admin"], false);
+
+    let identities = identities(&pool).await;
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].1, "jane");
+}
+
+#[sqlx::test]
+async fn state_has_to_match(pool: PgPool) {
+    let client = client(&pool).await;
+    let provider = provider();
+
+    let authorization = start(&client).await;
+    let code = provider.issue(&authorization.nonce, json!({ "sub": "jane" }));
+    assert_eq!(
+        callback(&client, &code, "forged").await.as_deref(),
+        Some("single sign-on expired, try again")
+    );
+
+    // the pending login is gone after a failed callback
+    assert_eq!(
+        callback(&client, &code, &authorization.state)
+            .await
+            .as_deref(),
+        Some("single sign-on expired, try again")
+    );
+    assert!(current_user(&client).await.is_none());
+    assert!(identities(&pool).await.is_empty());
+}
+
+#[sqlx::test]
+async fn nonce_has_to_match(pool: PgPool) {
+    let client = client(&pool).await;
+    let provider = provider();
+
+    let authorization = start(&client).await;
+    let code = provider.issue("replayed", json!({ "sub": "jane" }));
+    assert_eq!(
+        callback(&client, &code, &authorization.state)
+            .await
+            .as_deref(),
+        Some("identity provider returned an invalid id token")
+    );
+    assert!(current_user(&client).await.is_none());
+    assert!(identities(&pool).await.is_empty());
+}
+
+#[sqlx::test]
+async fn provisioning_doesnt_take_over_existing_logins(pool: PgPool) {
+    let bob = create_user(&pool, "bob").await;
+    let client = client(&pool).await;
+    let provider = provider();
+
+    let authorization = start(&client).await;
+    let code = provider.issue(
+        &authorization.nonce,
+        json!({ "sub": "bob", "preferred_username": "bob", "groups": ["admins"] }),
+    );
+    assert_eq!(
+        callback(&client, &code, &authorization.state)
+            .await
+            .as_deref(),
+        Some("an account with this login already exists, log in and link it from your profile")
+    );
+    assert!(current_user(&client).await.is_none());
+    assert!(identities(&pool).await.is_empty());
+    let admin: bool = sqlx::query_scalar("SELECT admin FROM users WHERE id = $1")
+        .bind(bob.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(!admin);
+}
+
+#[sqlx::test]
+async fn linking_keeps_admin_rights(pool: PgPool) {
+    let bob = create_user(&pool, "bob").await;
+    let client = client(&pool).await;
+    let provider = provider();
+    login(&client, "bob").await;
+
+    let authorization = start(&client).await;
+    let code = provider.issue(
+        &authorization.nonce,
+        json!({ "sub": "bob-at-provider", "groups": ["admins"] }),
+    );
+    assert_eq!(callback(&client, &code, &authorization.state).await, None);
+    assert_eq!(
+        identities(&pool).await,
+        vec![(bob.id, "bob-at-provider".to_string())]
+    );
+    let user = current_user(&client).await.unwrap();
+    assert_eq!(user["admin"], false);
+
+    // later logins through the provider apply the claim
+    let client = common::client(&pool).await;
+    let authorization = start(&client).await;
+    let code = provider.issue(
+        &authorization.nonce,
+        json!({ "sub": "bob-at-provider", "groups": ["admins"] }),
+    );
+    assert_eq!(callback(&client, &code, &authorization.state).await, None);
+    let user = current_user(&client).await.unwrap();
+    assert_eq!(user["login"], "bob");
+    assert_eq!(user["admin"], true);
+}
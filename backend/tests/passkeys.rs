@@ -0,0 +1,182 @@
+// registers a passkey with a software authenticator and logs in with it, the way a browser would
+mod common;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use common::{client, create_user, login, ORIGIN};
+use coset::cbor::Value;
+use coset::{iana, CborSerializable, CoseKeyBuilder};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use rand::rngs::OsRng;
+use rocket::http::{ContentType, Status};
+use rocket::local::asynchronous::{Client, LocalResponse};
+use rocket::serde::json::{json, serde_json};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+const RP_ID: &str = "assets.example.com";
+const FLAGS_REGISTRATION: u8 = 0x45; // user present, user verified, attested credential data
+const FLAGS_LOGIN: u8 = 0x05; // user present, user verified
+
+struct SoftAuthenticator {
+    key: SigningKey,
+    credential_id: Vec<u8>,
+    sign_count: u32,
+}
+
+impl SoftAuthenticator {
+    fn new() -> Self {
+        Self {
+            key: SigningKey::random(&mut OsRng),
+            credential_id: b"soft authenticator credential".to_vec(),
+            sign_count: 0,
+        }
+    }
+
+    fn authenticator_data(&self, flags: u8) -> Vec<u8> {
+        let mut data = Sha256::digest(RP_ID.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        data
+    }
+
+    fn client_data(kind: &str, challenge: &str) -> Vec<u8> {
+        json!({ "type": kind, "challenge": challenge, "origin": ORIGIN })
+            .to_string()
+            .into_bytes()
+    }
+
+    // `PublicKeyCredential.toJSON()` of `navigator.credentials.create`
+    fn create(&self, challenge: &str) -> serde_json::Value {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let cose_key = CoseKeyBuilder::new_ec2_pub_key(
+            iana::EllipticCurve::P_256,
+            point.x().unwrap().to_vec(),
+            point.y().unwrap().to_vec(),
+        )
+        .algorithm(iana::Algorithm::ES256)
+        .build()
+        .to_vec()
+        .unwrap();
+
+        let mut auth_data = self.authenticator_data(FLAGS_REGISTRATION);
+        auth_data.extend_from_slice(&[0; 16]);
+        auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&self.credential_id);
+        auth_data.extend_from_slice(&cose_key);
+
+        let attestation_object = Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text("none".into())),
+            (Value::Text("attStmt".into()), Value::Map(vec![])),
+            (Value::Text("authData".into()), Value::Bytes(auth_data)),
+        ]);
+        let mut attestation_bytes = Vec::new();
+        coset::cbor::ser::into_writer(&attestation_object, &mut attestation_bytes).unwrap();
+
+        json!({
+            "rawId": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(Self::client_data("webauthn.create", challenge)),
+                "attestationObject": URL_SAFE_NO_PAD.encode(attestation_bytes),
+            },
+        })
+    }
+
+    // `PublicKeyCredential.toJSON()` of `navigator.credentials.get`
+    fn get(&mut self, challenge: &str) -> serde_json::Value {
+        self.sign_count += 1;
+        let auth_data = self.authenticator_data(FLAGS_LOGIN);
+        let client_data = Self::client_data("webauthn.get", challenge);
+        let mut message = auth_data.clone();
+        message.extend_from_slice(&Sha256::digest(&client_data));
+        let signature: Signature = self.key.sign(&message);
+
+        json!({
+            "rawId": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+                "signature": URL_SAFE_NO_PAD.encode(signature.to_der()),
+            },
+        })
+    }
+}
+
+async fn post_json<'c>(client: &'c Client, uri: &'static str, body: String) -> LocalResponse<'c> {
+    client
+        .post(uri)
+        .header(ContentType::JSON)
+        .body(body)
+        .dispatch()
+        .await
+}
+
+async fn assert_challenge_used_up(response: LocalResponse<'_>) {
+    assert_eq!(response.status(), Status::BadRequest);
+    let body: serde_json::Value = response.into_json().await.unwrap();
+    assert_eq!(body["detail"], "passkey challenge expired, try again");
+}
+
+async fn challenge_of(response: LocalResponse<'_>) -> String {
+    assert_eq!(response.status(), Status::Ok);
+    let options: serde_json::Value = response.into_json().await.unwrap();
+    options["challenge"].as_str().unwrap().to_string()
+}
+
+#[sqlx::test]
+async fn register_and_log_in_with_passkey(pool: PgPool) {
+    let user = create_user(&pool, "alice").await;
+    let client = client(&pool).await;
+    login(&client, "alice").await;
+    let mut authenticator = SoftAuthenticator::new();
+
+    let options = client.post("/api/user/passkey/options").dispatch().await;
+    let challenge = challenge_of(options).await;
+    let challenge_cookie = client
+        .cookies()
+        .get_private("passkey_registration")
+        .unwrap();
+    let registration =
+        json!({ "name": "soft key", "credential": authenticator.create(&challenge) }).to_string();
+    let response = post_json(&client, "/api/user/passkey", registration.clone()).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    // the same answer with the same cookie again, the challenge was used up on the server
+    let response = client
+        .post("/api/user/passkey")
+        .header(ContentType::JSON)
+        .private_cookie(challenge_cookie)
+        .body(registration)
+        .dispatch()
+        .await;
+    assert_challenge_used_up(response).await;
+
+    client.post("/api/logout").dispatch().await;
+    assert_eq!(
+        client.get("/api/user").dispatch().await.status(),
+        Status::Unauthorized
+    );
+
+    let options = client.post("/api/login/passkey/options").dispatch().await;
+    let challenge = challenge_of(options).await;
+    let challenge_cookie = client.cookies().get_private("passkey_login").unwrap();
+    let assertion = authenticator.get(&challenge).to_string();
+    let response = post_json(&client, "/api/login/passkey", assertion.clone()).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = client.get("/api/user").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let me: serde_json::Value = response.into_json().await.unwrap();
+    assert_eq!(me["user_id"], json!(user.id));
+
+    client.post("/api/logout").dispatch().await;
+    let response = client
+        .post("/api/login/passkey")
+        .header(ContentType::JSON)
+        .private_cookie(challenge_cookie)
+        .body(assertion)
+        .dispatch()
+        .await;
+    assert_challenge_used_up(response).await;
+}
@@ -0,0 +1,116 @@
+// reset links from an admin set a new password once and log the user out everywhere
+mod common;
+
+use common::{client, create_admin, create_user, login, PASSWORD};
+use rocket::http::{ContentType, Status};
+use rocket::local::asynchronous::Client;
+use rocket::serde::json::{json, serde_json};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+async fn create_reset(admin: &Client, user_id: Uuid) -> String {
+    let response = admin
+        .post("/api/user/password/reset-token")
+        .header(ContentType::JSON)
+        .body(json!({ "id": user_id }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let reset: serde_json::Value = response.into_json().await.unwrap();
+    reset["token"].as_str().unwrap().to_string()
+}
+
+async fn use_reset(client: &Client, token: &str, new_password: &str) -> Status {
+    client
+        .post("/api/password/reset")
+        .header(ContentType::JSON)
+        .body(json!({ "token": token, "new_password": new_password }).to_string())
+        .dispatch()
+        .await
+        .status()
+}
+
+async fn login_with(client: &Client, login: &str, password: &str) -> Status {
+    client
+        .post("/api/login")
+        .header(ContentType::JSON)
+        .body(json!({ "login": login, "password": password }).to_string())
+        .dispatch()
+        .await
+        .status()
+}
+
+async fn admin_client(pool: &PgPool) -> Client {
+    create_admin(pool, "admin").await;
+    let admin = client(pool).await;
+    login(&admin, "admin").await;
+    admin
+}
+
+#[sqlx::test]
+async fn reset_link_works_once(pool: PgPool) {
+    let alice = create_user(&pool, "alice").await;
+    let admin = admin_client(&pool).await;
+    let token = create_reset(&admin, alice.id).await;
+
+    let session = client(&pool).await;
+    login(&session, "alice").await;
+
+    let anonymous = client(&pool).await;
+    assert_eq!(
+        use_reset(&anonymous, &token, "new password").await,
+        Status::Ok
+    );
+    assert_eq!(
+        use_reset(&anonymous, &token, "other password").await,
+        Status::Gone
+    );
+
+    assert_eq!(
+        session.get("/api/user").dispatch().await.status(),
+        Status::Unauthorized
+    );
+    assert_eq!(
+        login_with(&anonymous, "alice", PASSWORD).await,
+        Status::BadRequest
+    );
+    assert_eq!(
+        login_with(&anonymous, "alice", "new password").await,
+        Status::Ok
+    );
+}
+
+#[sqlx::test]
+async fn rejected_password_keeps_the_link(pool: PgPool) {
+    let alice = create_user(&pool, "alice").await;
+    let admin = admin_client(&pool).await;
+    let token = create_reset(&admin, alice.id).await;
+
+    let anonymous = client(&pool).await;
+    assert_eq!(
+        use_reset(&anonymous, &token, "short").await,
+        Status::BadRequest
+    );
+    assert_eq!(
+        use_reset(&anonymous, &token, "new password").await,
+        Status::Ok
+    );
+}
+
+#[sqlx::test]
+async fn new_link_replaces_the_old_one(pool: PgPool) {
+    let alice = create_user(&pool, "alice").await;
+    let admin = admin_client(&pool).await;
+    let old = create_reset(&admin, alice.id).await;
+    let new = create_reset(&admin, alice.id).await;
+
+    let anonymous = client(&pool).await;
+    assert_eq!(
+        use_reset(&anonymous, &old, "new password").await,
+        Status::Gone
+    );
+    assert_eq!(
+        use_reset(&anonymous, &new, "new password").await,
+        Status::Ok
+    );
+}
@@ -0,0 +1,97 @@
+// admins that have to use 2FA get admin rights only after they set it up
+mod common;
+
+use common::{client, create_admin, create_user, login, login_without_password, PASSWORD};
+use lempek_assets_backend::auth::endpoints::insert_user_without_password;
+use rocket::http::{ContentType, Status};
+use rocket::local::asynchronous::Client;
+use rocket::serde::json::{json, serde_json};
+use sqlx::PgPool;
+use totp_rs::TOTP;
+use uuid::Uuid;
+
+async fn require_admin_2fa(pool: &PgPool) {
+    sqlx::query("UPDATE settings SET require_admin_2fa = TRUE")
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+async fn create_folder_of(pool: &PgPool, owner: Uuid) -> Uuid {
+    sqlx::query_scalar("INSERT INTO folders (name, owner_id) VALUES ('private', $1) RETURNING id")
+        .bind(owner)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+async fn visible_folders(client: &Client) -> Vec<serde_json::Value> {
+    let response = client.get("/api/folders/all").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    response.into_json().await.unwrap()
+}
+
+async fn enrol(client: &Client, password: &str) {
+    let response = client
+        .post("/api/user/2fa/setup")
+        .header(ContentType::JSON)
+        .body(json!({ "password": password }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let setup: serde_json::Value = response.into_json().await.unwrap();
+    let totp = TOTP::from_url(setup["uri"].as_str().unwrap()).unwrap();
+
+    let response = client
+        .post("/api/user/2fa/enable")
+        .header(ContentType::JSON)
+        .body(json!({ "code": totp.generate_current().unwrap() }).to_string())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[sqlx::test]
+async fn admin_without_required_2fa_has_only_granted_rights(pool: PgPool) {
+    create_admin(&pool, "admin").await;
+    let bob = create_user(&pool, "bob").await;
+    let folder = create_folder_of(&pool, bob.id).await;
+    require_admin_2fa(&pool).await;
+
+    let admin = client(&pool).await;
+    login(&admin, "admin").await;
+    assert!(visible_folders(&admin).await.is_empty());
+    let response = admin.get("/api/user/lockouts").dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let body: serde_json::Value = response.into_json().await.unwrap();
+    assert_eq!(
+        body["detail"],
+        "two-factor authentication is required for admin accounts"
+    );
+
+    enrol(&admin, PASSWORD).await;
+    let folders = visible_folders(&admin).await;
+    assert_eq!(folders.len(), 1);
+    assert_eq!(folders[0]["id"], json!(folder));
+    let response = admin.get("/api/user/lockouts").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[sqlx::test]
+async fn admin_without_password_can_enrol(pool: PgPool) {
+    let mut conn = pool.acquire().await.unwrap();
+    let user = insert_user_without_password(&mut conn, "sso", true)
+        .await
+        .unwrap();
+    drop(conn);
+    require_admin_2fa(&pool).await;
+
+    let admin = client(&pool).await;
+    login_without_password(&admin, &pool, &user).await;
+    let response = admin.get("/api/user/lockouts").dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    enrol(&admin, "").await;
+    let response = admin.get("/api/user/lockouts").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+}
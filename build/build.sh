@@ -33,8 +33,12 @@ FILES_DIR=${FILES_DIR}
 ALLOWED_ORIGIN=${PUBLIC_FRONTEND_URL}
 ROCKET_PORT=${BACKEND_PORT}
 ROCKET_SECRET_KEY=${SECRET_KEY}
+ACCEL_REDIRECT_LOCATION=${ACCEL_REDIRECT_LOCATION}
+TRUSTED_PROXIES=${TRUSTED_PROXIES}
+TRUSTED_PROXY_HEADER=${TRUSTED_PROXY_HEADER}
+GEOIP_DATABASE=${GEOIP_DATABASE:-GeoLite2-City.mmdb}
 EOF
-cp ./Rocket.toml ./target/release/lempek-assets-backend .env "../$BACKDIR"
+cp ./Rocket.toml ./target/release/lempek-assets-backend ./target/release/lempek-assets-admin .env "../$BACKDIR"
 rm .env
 cd ..
 
@@ -1,6 +1,6 @@
 import {useAuthStore} from "~/stores/auth";
-const publicRoutes = ['/changelog', '/login'];
-const notLoggedRoutes = ['/login'];
+const publicRoutes = ['/changelog', '/login', '/setup', '/invite', '/reset-password'];
+const notLoggedRoutes = ['/login', '/setup', '/invite', '/reset-password'];
 
 export default defineNuxtRouteMiddleware(async (to) => {
     const auth = useAuthStore();
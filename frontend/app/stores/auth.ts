@@ -27,7 +27,7 @@ export const useAuthStore = defineStore('auth', {
             }
         },
 
-        async login(credentials: { login: string; password: string }): Promise<ApiResponse> {
+        async login(credentials: { login: string; password: string; stay_logged_in: boolean }): Promise<ApiResponse> {
             const config = useRuntimeConfig();
             try {
                 return await $fetch<ApiResponse>(config.public.apiBase + '/login', {
@@ -47,7 +47,58 @@ export const useAuthStore = defineStore('auth', {
             }
         },
 
-        async changePassword(credentials: { current_password: string, new_password: string }): Promise<ApiResponse> {
+        async loginTwoFactor(code: string): Promise<ApiResponse> {
+            const config = useRuntimeConfig();
+            try {
+                return await $fetch<ApiResponse>(config.public.apiBase + '/login/2fa', {
+                    method: 'POST',
+                    credentials: 'include',
+                    body: {code}
+                });
+            } catch (error: any) {
+                if (error?.data) {
+                    return error.data as ApiResponse;
+                }
+                return {
+                    success: false,
+                    detail: 'Nie udało się zweryfikować kodu (błąd sieci).',
+                    err_id: null
+                };
+            }
+        },
+
+        // passwordless login, or the second step after the password when 2FA is enabled
+        async loginPasskey(stayLoggedIn: boolean): Promise<ApiResponse> {
+            const config = useRuntimeConfig();
+            try {
+                const options = await $fetch<any>(config.public.apiBase + '/login/passkey/options', {
+                    method: 'POST',
+                    credentials: 'include'
+                });
+                const credential = await navigator.credentials.get({
+                    publicKey: PublicKeyCredential.parseRequestOptionsFromJSON(options)
+                }) as PublicKeyCredential | null;
+                if (!credential) {
+                    return {success: false, detail: 'Logowanie kluczem dostępu zostało przerwane.', err_id: null};
+                }
+                return await $fetch<ApiResponse>(config.public.apiBase + '/login/passkey', {
+                    method: 'POST',
+                    credentials: 'include',
+                    body: {...credential.toJSON(), stay_logged_in: stayLoggedIn}
+                });
+            } catch (error: any) {
+                if (error?.data) {
+                    return error.data as ApiResponse;
+                }
+                return {
+                    success: false,
+                    detail: 'Nie udało się zalogować kluczem dostępu.',
+                    err_id: null
+                };
+            }
+        },
+
+        async changePassword(credentials: { current_password: string, new_password: string, revoke_other_sessions: boolean }): Promise<ApiResponse> {
             const config = useRuntimeConfig();
             try {
                 return await $fetch<ApiResponse>(config.public.apiBase + '/user/password', {
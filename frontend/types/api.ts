@@ -1,8 +1,11 @@
+export type Visibility = 'inherit' | 'public' | 'private';
+
 export type Folder = {
     id: string;
     parent_id: string | null;
     owner_id: string;
     name: string;
+    visibility: Visibility;
     created_at: string;
     updated_at: string;
 };
@@ -47,6 +50,7 @@ export type RefreshToken = {
     city: string | null;
     expires_at: string;
     created_at: string;
+    current: boolean;
 }
 
 export type UserAll = {